@@ -0,0 +1,287 @@
+//! Configuration of a machine
+//!
+//! Everything the host can set on a `Machine` between transfers, in one place. A configuration is built in code from
+//! its default with the `with_` methods, or, with the `serde` feature, loaded from a configuration file. Durations
+//! are given in seconds in a file, and may have a fraction.
+
+use std::time::Duration;
+
+use crate::constants::{
+    DEFAULT_MAX_RETRIES, DEFAULT_RETRANSMIT_TIMEOUT, MIN_BLOCK_SIZE, Mode, Rollover, Strictness,
+};
+use crate::limits::TransferLimits;
+use crate::options::{BLOCK_SIZE_OPTION, ROLLOVER_OPTION, TIMEOUT_OPTION};
+use crate::options::{TRANSFER_SIZE_OPTION, WINDOW_SIZE_OPTION};
+use crate::options::{TransferOption, parse_block_size, parse_window_size};
+
+/// Which options the machine accepts by itself when a remote peer requests them. By default, none are, and the host
+/// accepts them one by one with `accept_option()`.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize), serde(default))]
+pub struct OptionPolicy {
+    /// The largest block size to accept. A larger request is lowered to it.
+    pub max_block_size: Option<u16>,
+    /// The largest window size to accept. A larger request is lowered to it.
+    pub max_window_size: Option<u16>,
+    /// Whether the timeout the peer proposes is accepted.
+    pub timeout: bool,
+    /// Whether the transfer size option is accepted.
+    pub transfer_size: bool,
+    /// Whether the rollover the peer proposes is accepted.
+    pub rollover: bool,
+}
+
+impl OptionPolicy {
+    /// The reply to a requested option, if the policy accepts it.
+    pub(crate) fn reply(&self, requested: &TransferOption) -> Option<TransferOption> {
+        let reply = if requested.is(BLOCK_SIZE_OPTION) {
+            let size = parse_block_size(&requested.value)?.min(self.max_block_size?.into());
+            TransferOption::new(BLOCK_SIZE_OPTION, size.max(MIN_BLOCK_SIZE).to_string())
+        } else if requested.is(WINDOW_SIZE_OPTION) {
+            let size = parse_window_size(&requested.value)?.min(self.max_window_size?);
+            TransferOption::window_size(size)
+        } else if (requested.is(TIMEOUT_OPTION) && self.timeout)
+            || (requested.is(TRANSFER_SIZE_OPTION) && self.transfer_size)
+            || (requested.is(ROLLOVER_OPTION) && self.rollover)
+        {
+            requested.clone()
+        } else {
+            return None;
+        };
+        reply.answers(requested).then_some(reply)
+    }
+}
+
+/// The settings of a machine. The default is what `Machine::new()` starts with.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize), serde(default))]
+pub struct MachineConfig {
+    /// The file mode of the requests the host makes.
+    pub mode: Mode,
+    /// The options to propose in the requests the host makes.
+    pub options: Vec<TransferOption>,
+    /// Which options requested by a remote peer are accepted without the host's involvement.
+    pub accept: OptionPolicy,
+    /// The interval after which an unanswered message is retransmitted, unless the peers negotiate a timeout.
+    #[cfg_attr(feature = "serde", serde(with = "seconds"))]
+    pub retransmit_timeout: Duration,
+    /// Whether the retransmission timeout adapts to the measured round trip time.
+    pub adaptive_timeout: bool,
+    /// The number of consecutive retransmissions after which a transfer fails.
+    pub max_retries: u32,
+    /// How long the host keeps acknowledging the final block after it received a file.
+    #[cfg_attr(feature = "serde", serde(with = "seconds"))]
+    pub dally_duration: Duration,
+    /// How closely remote peers are held to the RFC.
+    pub strictness: Strictness,
+    /// Whether a message from the peer that breaks the protocol is answered with an illegal operation error.
+    pub reply_to_violations: bool,
+    /// The block number that follows block 65535, unless the peers negotiate otherwise.
+    pub rollover: Rollover,
+    /// Whether the sender adapts its window to losses.
+    pub congestion_control: bool,
+    /// The bounds on the size, duration and retransmissions of each transfer.
+    pub limits: TransferLimits,
+}
+
+impl Default for MachineConfig {
+    fn default() -> Self {
+        Self {
+            mode: Mode::default(),
+            options: Vec::new(),
+            accept: OptionPolicy::default(),
+            retransmit_timeout: DEFAULT_RETRANSMIT_TIMEOUT,
+            adaptive_timeout: false,
+            max_retries: DEFAULT_MAX_RETRIES,
+            dally_duration: Duration::ZERO,
+            strictness: Strictness::default(),
+            reply_to_violations: false,
+            rollover: Rollover::default(),
+            congestion_control: false,
+            limits: TransferLimits::default(),
+        }
+    }
+}
+
+impl MachineConfig {
+    /// Sets the file mode of the requests the host makes.
+    pub fn with_mode(mut self, mode: Mode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    /// Sets the options to propose in the requests the host makes.
+    pub fn with_options(mut self, options: Vec<TransferOption>) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// Sets which options requested by a remote peer are accepted without the host's involvement.
+    pub fn with_accept(mut self, accept: OptionPolicy) -> Self {
+        self.accept = accept;
+        self
+    }
+
+    /// Sets the interval after which an unanswered message is retransmitted.
+    pub fn with_retransmit_timeout(mut self, timeout: Duration) -> Self {
+        self.retransmit_timeout = timeout;
+        self
+    }
+
+    /// Sets whether the retransmission timeout adapts to the measured round trip time.
+    pub fn with_adaptive_timeout(mut self, adaptive: bool) -> Self {
+        self.adaptive_timeout = adaptive;
+        self
+    }
+
+    /// Sets the number of consecutive retransmissions after which a transfer fails.
+    pub fn with_max_retries(mut self, retries: u32) -> Self {
+        self.max_retries = retries;
+        self
+    }
+
+    /// Sets how long the host keeps acknowledging the final block after it received a file.
+    pub fn with_dally_duration(mut self, duration: Duration) -> Self {
+        self.dally_duration = duration;
+        self
+    }
+
+    /// Sets how closely remote peers are held to the RFC.
+    pub fn with_strictness(mut self, strictness: Strictness) -> Self {
+        self.strictness = strictness;
+        self
+    }
+
+    /// Sets whether a message from the peer that breaks the protocol is answered with an error.
+    pub fn with_reply_to_violations(mut self, reply: bool) -> Self {
+        self.reply_to_violations = reply;
+        self
+    }
+
+    /// Sets the block number that follows block 65535.
+    pub fn with_rollover(mut self, rollover: Rollover) -> Self {
+        self.rollover = rollover;
+        self
+    }
+
+    /// Sets whether the sender adapts its window to losses.
+    pub fn with_congestion_control(mut self, enabled: bool) -> Self {
+        self.congestion_control = enabled;
+        self
+    }
+
+    /// Sets the bounds on the size, duration and retransmissions of each transfer.
+    pub fn with_limits(mut self, limits: TransferLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+}
+
+/// Reads a duration from a number of seconds.
+#[cfg(feature = "serde")]
+pub(crate) mod seconds {
+    use std::time::Duration;
+
+    use serde::{Deserialize, Deserializer, de::Error};
+
+    pub(crate) fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Duration, D::Error> {
+        Duration::try_from_secs_f64(f64::deserialize(deserializer)?).map_err(D::Error::custom)
+    }
+}
+
+/// Reads an optional duration from a number of seconds.
+#[cfg(feature = "serde")]
+pub(crate) mod optional_seconds {
+    use std::time::Duration;
+
+    use serde::{Deserialize, Deserializer, de::Error};
+
+    pub(crate) fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<Duration>, D::Error> {
+        Option::<f64>::deserialize(deserializer)?
+            .map(|seconds| Duration::try_from_secs_f64(seconds).map_err(D::Error::custom))
+            .transpose()
+    }
+}
+
+mod test {
+    #[cfg(test)]
+    use super::*;
+
+    #[test]
+    fn test_option_policy() {
+        let policy = OptionPolicy {
+            max_block_size: Some(1428),
+            max_window_size: Some(8),
+            transfer_size: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            policy.reply(&TransferOption::block_size(8192)),
+            Some(TransferOption::block_size(1428))
+        );
+        assert_eq!(
+            policy.reply(&TransferOption::block_size(512)),
+            Some(TransferOption::block_size(512))
+        );
+        assert_eq!(
+            policy.reply(&TransferOption::window_size(4)),
+            Some(TransferOption::window_size(4))
+        );
+        assert_eq!(
+            policy.reply(&TransferOption::transfer_size(100)),
+            Some(TransferOption::transfer_size(100))
+        );
+        assert_eq!(policy.reply(&TransferOption::timeout(5)), None);
+        assert_eq!(policy.reply(&TransferOption::new("colour", "blue")), None);
+        assert_eq!(
+            OptionPolicy::default().reply(&TransferOption::block_size(8192)),
+            None
+        );
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_load_config() {
+        let config: MachineConfig = toml::from_str(
+            r#"
+            mode = "netascii"
+            retransmit_timeout = 0.5
+            dally_duration = 3
+            strictness = "lenient"
+            options = [{ name = "blksize", value = "1428" }]
+
+            [accept]
+            max_block_size = 1428
+            transfer_size = true
+
+            [limits]
+            max_bytes = 1048576
+            max_duration = 60
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            config,
+            MachineConfig::default()
+                .with_mode(Mode::Text)
+                .with_retransmit_timeout(Duration::from_millis(500))
+                .with_dally_duration(Duration::from_secs(3))
+                .with_strictness(Strictness::Lenient)
+                .with_options(vec![TransferOption::block_size(1428)])
+                .with_accept(OptionPolicy {
+                    max_block_size: Some(1428),
+                    transfer_size: true,
+                    ..Default::default()
+                })
+                .with_limits(TransferLimits {
+                    max_bytes: Some(1048576),
+                    max_duration: Some(Duration::from_secs(60)),
+                    ..Default::default()
+                })
+        );
+    }
+}
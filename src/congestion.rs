@@ -0,0 +1,105 @@
+//! Congestion control for windowed transfers
+//!
+//! The sender starts with a window of one block, grows it by one block for every window that is acknowledged in
+//! full, and halves it when a loss shows up (additive increase, multiplicative decrease). A loss shows up as a
+//! timeout, or as an ack for a block before the end of the window. The window never grows past the window size
+//! negotiated with the peer (RFC 7440).
+
+/// The state of the congestion window, for tuning.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct CongestionStats {
+    /// The number of blocks sent before waiting for an ack, unless the negotiated window size is smaller.
+    pub window: u16,
+    /// The number of times the window grew after a window was acknowledged in full.
+    pub increases: u64,
+    /// The number of times the window shrank after a loss.
+    pub decreases: u64,
+}
+
+impl Default for CongestionStats {
+    fn default() -> Self {
+        Self {
+            window: 1,
+            increases: 0,
+            decreases: 0,
+        }
+    }
+}
+
+/// Tracks the congestion window of the sender.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub(crate) struct CongestionWindow {
+    // The current window, and how it got there.
+    stats: CongestionStats,
+    // Whether the window already shrank since the last window that was acknowledged in full. The acks that follow
+    // a loss often report the same loss again, so it only counts once.
+    reduced: bool,
+}
+
+impl CongestionWindow {
+    /// The number of blocks to send before waiting for an ack, given the negotiated window size.
+    pub(crate) fn window(&self, limit: u16) -> u16 {
+        self.stats.window.min(limit)
+    }
+
+    /// Grows the window by one block after a window was acknowledged in full.
+    pub(crate) fn grow(&mut self, limit: u16) {
+        self.reduced = false;
+        if self.stats.window < limit {
+            self.stats.window += 1;
+            self.stats.increases += 1;
+        }
+    }
+
+    /// Halves the window after a partial or duplicate ack, unless it already shrank for the same loss.
+    pub(crate) fn shrink(&mut self) {
+        if self.reduced {
+            return;
+        }
+        self.reduced = true;
+        self.stats.window = (self.stats.window / 2).max(1);
+        self.stats.decreases += 1;
+    }
+
+    /// Halves the window after the whole window went unanswered. Every timeout is a new loss.
+    pub(crate) fn time_out(&mut self) {
+        self.reduced = false;
+        self.shrink();
+    }
+
+    /// The current window, and how it got there.
+    pub(crate) fn stats(&self) -> CongestionStats {
+        self.stats
+    }
+}
+
+mod test {
+    #[cfg(test)]
+    use super::*;
+
+    #[test]
+    fn test_additive_increase_multiplicative_decrease() {
+        let mut congestion = CongestionWindow::default();
+        assert_eq!(congestion.window(4), 1);
+        for _ in 0..5 {
+            congestion.grow(4);
+        }
+        assert_eq!(congestion.window(4), 4);
+        assert_eq!(congestion.stats().increases, 3);
+        // A loss that is reported twice only counts once.
+        congestion.shrink();
+        congestion.shrink();
+        assert_eq!(congestion.window(4), 2);
+        congestion.time_out();
+        assert_eq!(congestion.window(4), 1);
+        congestion.time_out();
+        assert_eq!(
+            congestion.stats(),
+            CongestionStats {
+                window: 1,
+                increases: 3,
+                decreases: 3,
+            }
+        );
+    }
+}
@@ -2,17 +2,38 @@
 
 use crate::errors::TftprsError;
 
-pub const MAX_PACKET_SIZE: usize = 512;
+use std::time::Duration;
 
-/// TFTP supports five types of packets. The TFTP header of a packet contains the opcode associated with that packet.
+/// The size of the data in a full block, unless a different size is negotiated with the blksize option (RFC 2348).
+pub const DEFAULT_BLOCK_SIZE: usize = 512;
+/// The smallest block size that may be negotiated.
+pub const MIN_BLOCK_SIZE: usize = 8;
+/// The largest block size that may be negotiated.
+pub const MAX_BLOCK_SIZE: usize = 65464;
+/// The largest packet the machine will send or receive, which is a data packet with a block of the largest size.
+/// A buffer of this size can hold any message, whatever the negotiated block size.
+pub const MAX_PACKET_SIZE: usize = MAX_BLOCK_SIZE + FIXED_DATA_BYTES;
+
+/// The interval after which an unanswered message is retransmitted, unless the peers negotiated otherwise.
+pub const DEFAULT_RETRANSMIT_TIMEOUT: Duration = Duration::from_secs(1);
+/// The shortest interval an adaptive retransmission timeout may shrink to.
+pub const MIN_RETRANSMIT_TIMEOUT: Duration = Duration::from_millis(10);
+/// The longest interval an adaptive retransmission timeout may grow to, including backoff.
+pub const MAX_RETRANSMIT_TIMEOUT: Duration = Duration::from_secs(60);
+/// The number of consecutive retransmissions after which a transfer is abandoned.
+pub const DEFAULT_MAX_RETRIES: u32 = 5;
+
+/// TFTP supports five types of packets, plus the option acknowledgement from RFC 2347. The TFTP header of a packet
+/// contains the opcode associated with that packet.
 #[repr(u16)]
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
-pub(crate) enum OpCode {
+pub enum OpCode {
     ReadRequest = 1,
     WriteRequest = 2,
     Data = 3,
     Acknowledgement = 4,
     Error = 5,
+    OptionAcknowledgement = 6,
 }
 
 impl TryFrom<u16> for OpCode {
@@ -25,6 +46,7 @@ impl TryFrom<u16> for OpCode {
             3 => Ok(OpCode::Data),
             4 => Ok(OpCode::Acknowledgement),
             5 => Ok(OpCode::Error),
+            6 => Ok(OpCode::OptionAcknowledgement),
             _ => Err(TftprsError::BadPacketReceived),
         }
     }
@@ -32,20 +54,151 @@ impl TryFrom<u16> for OpCode {
 
 pub(crate) const TEXT_MODE: &str = "NETASCII";
 pub(crate) const BINARY_MODE: &str = "OCTET";
+pub(crate) const MAIL_MODE: &str = "MAIL";
+/// Names that some clients send for netascii mode, although the RFC does not define them.
+const TEXT_MODE_ALIASES: [&str; 2] = ["ASCII", "TEXT"];
+/// Names that some clients send for octet mode, although the RFC does not define them.
+const BINARY_MODE_ALIASES: [&str; 2] = ["BINARY", "IMAGE"];
 pub(crate) const FIXED_REQUEST_BYTES: usize = 4;
 pub(crate) const FIXED_DATA_BYTES: usize = 4;
-pub(crate) const MAX_DATA_SIZE: usize = MAX_PACKET_SIZE - FIXED_DATA_BYTES;
+/// Requests, including any options, must fit in the packet size of RFC 1350.
+pub(crate) const MAX_REQUEST_SIZE: usize = DEFAULT_BLOCK_SIZE;
 
 /// The mode field contains the string "netascii", "octet", or "mail"
 /// (or any combination of upper and lower case, such as "NETASCII", NetAscii", etc.)
 /// in netascii indicating the three modes defined in the protocol.
 #[derive(Debug, Copy, Clone, Default, PartialEq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Deserialize),
+    serde(rename_all = "lowercase")
+)]
 pub enum Mode {
     /// A host which receives netascii mode data must translate the data to its own format.
+    /// The machine translates between LF line endings in the file and CR LF on the wire.
+    #[cfg_attr(feature = "serde", serde(alias = "netascii"))]
     Text,
     /// Octet mode is used to transfer a file that is in the 8-bit format of the machine from which the file is being transferred.
     #[default]
+    #[cfg_attr(feature = "serde", serde(alias = "octet"))]
     Binary,
+    /// Mail mode sends a message to a user rather than a file. The filename of the request names the recipient, and
+    /// the message is sent as netascii. It may only be used to write.
+    Mail,
+}
+
+impl Mode {
+    /// The name of the mode in a request.
+    pub(crate) fn wire_name(&self) -> &'static str {
+        match self {
+            Mode::Text => TEXT_MODE,
+            Mode::Binary => BINARY_MODE,
+            Mode::Mail => MAIL_MODE,
+        }
+    }
+
+    /// Indicates whether data is translated to netascii on the wire.
+    pub(crate) fn is_netascii(&self) -> bool {
+        matches!(self, Mode::Text | Mode::Mail)
+    }
+
+    /// Parses the mode field of a request, ignoring case. Common aliases are only accepted by the lenient profile.
+    pub(crate) fn parse(mode: &str, strictness: Strictness) -> Option<Mode> {
+        let matches = |names: &[&str]| names.iter().any(|name| mode.eq_ignore_ascii_case(name));
+        if matches(&[TEXT_MODE]) {
+            Some(Mode::Text)
+        } else if matches(&[BINARY_MODE]) {
+            Some(Mode::Binary)
+        } else if matches(&[MAIL_MODE]) {
+            Some(Mode::Mail)
+        } else if strictness == Strictness::Lenient && matches(&TEXT_MODE_ALIASES) {
+            Some(Mode::Text)
+        } else if strictness == Strictness::Lenient && matches(&BINARY_MODE_ALIASES) {
+            Some(Mode::Binary)
+        } else {
+            None
+        }
+    }
+}
+
+/// How closely the machine holds remote peers to the RFC.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Deserialize),
+    serde(rename_all = "lowercase")
+)]
+pub enum Strictness {
+    /// Only what the RFC defines is accepted.
+    #[default]
+    Strict,
+    /// Common deviations of broken clients are tolerated, such as the "binary" and "ascii" modes, and packets padded
+    /// with zeros.
+    Lenient,
+}
+
+/// The block field of a packet is only 16 bits wide. To transfer a file of more than 65535 blocks, the block
+/// number rolls over once it reaches 65535. Implementations differ on the block number that follows.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Deserialize),
+    serde(rename_all = "snake_case")
+)]
+pub enum Rollover {
+    /// Block 65535 is followed by block 0. This is what most implementations do.
+    #[default]
+    ToZero,
+    /// Block 65535 is followed by block 1, since block 0 otherwise only acknowledges a request.
+    ToOne,
+}
+
+impl Rollover {
+    /// The number of distinct block numbers in one cycle of the block field.
+    fn period(self) -> i64 {
+        match self {
+            Rollover::ToZero => 1 << 16,
+            Rollover::ToOne => (1 << 16) - 1,
+        }
+    }
+
+    /// The block number on the wire for a block counted from the start of the transfer.
+    pub(crate) fn wire_block(self, block: u64) -> u16 {
+        match self {
+            Rollover::ToZero => block as u16,
+            Rollover::ToOne if block == 0 => 0,
+            Rollover::ToOne => ((block - 1) % u16::MAX as u64 + 1) as u16,
+        }
+    }
+
+    /// The block counted from the start of the transfer for a block number on the wire. Of all the blocks with
+    /// this number, the one nearest the reference block is chosen.
+    pub(crate) fn logical_block(self, wire_block: u16, reference: u64) -> u64 {
+        if self == Rollover::ToOne && wire_block == 0 {
+            return 0;
+        }
+        let period = self.period();
+        let mut delta = wire_block as i64 - self.wire_block(reference) as i64;
+        if delta > period / 2 {
+            delta -= period;
+        } else if delta <= -period / 2 {
+            delta += period;
+        }
+        (reference as i64 + delta).max(0) as u64
+    }
+
+    /// The block counted from the start of the transfer for a block number on the wire. Of all the blocks with
+    /// this number, the first one at or after the given block is chosen.
+    pub(crate) fn logical_block_from(self, wire_block: u16, first: u64) -> u64 {
+        let first = match self {
+            Rollover::ToZero => first,
+            Rollover::ToOne if wire_block == 0 => return 0,
+            // Block 0 is not part of the cycle.
+            Rollover::ToOne => first.max(1),
+        };
+        let delta = (wire_block as i64 - self.wire_block(first) as i64).rem_euclid(self.period());
+        first + delta as u64
+    }
 }
 
 /// The local interpretation, from the perspective of the host, of the currently active transfer.
@@ -71,6 +224,8 @@ pub enum ErrorCode {
     UnknownTransferId = 5,
     FileAlreadyExists = 6,
     NoSuchUser = 7,
+    /// Added by RFC 2347 for a transfer terminated due to option negotiation.
+    OptionNegotiation = 8,
 }
 
 impl TryFrom<u16> for ErrorCode {
@@ -85,7 +240,57 @@ impl TryFrom<u16> for ErrorCode {
             5 => Ok(ErrorCode::UnknownTransferId),
             6 => Ok(ErrorCode::FileAlreadyExists),
             7 => Ok(ErrorCode::NoSuchUser),
+            8 => Ok(ErrorCode::OptionNegotiation),
             _ => Ok(ErrorCode::Undefined),
         }
     }
 }
+
+mod test {
+    #[cfg(test)]
+    use super::*;
+
+    #[test]
+    fn test_parse_mode() {
+        assert_eq!(Mode::parse("octet", Strictness::Strict), Some(Mode::Binary));
+        assert_eq!(
+            Mode::parse("NetAscii", Strictness::Strict),
+            Some(Mode::Text)
+        );
+        assert_eq!(Mode::parse("mail", Strictness::Strict), Some(Mode::Mail));
+        assert_eq!(Mode::parse("binary", Strictness::Strict), None);
+        assert_eq!(
+            Mode::parse("Binary", Strictness::Lenient),
+            Some(Mode::Binary)
+        );
+        assert_eq!(Mode::parse("ascii", Strictness::Lenient), Some(Mode::Text));
+        assert_eq!(Mode::parse("ebcdic", Strictness::Lenient), None);
+    }
+
+    #[test]
+    fn test_rollover_to_zero() {
+        let rollover = Rollover::ToZero;
+        assert_eq!(rollover.wire_block(65535), 65535);
+        assert_eq!(rollover.wire_block(65536), 0);
+        assert_eq!(rollover.wire_block(65537), 1);
+        assert_eq!(rollover.logical_block(0, 65535), 65536);
+        assert_eq!(rollover.logical_block(65535, 65537), 65535);
+        assert_eq!(rollover.logical_block(2, 3 * 65536), 3 * 65536 + 2);
+        assert_eq!(rollover.logical_block(65534, 1), 0);
+        assert_eq!(rollover.logical_block_from(65534, 1), 65534);
+        assert_eq!(rollover.logical_block_from(1, 65535), 65537);
+    }
+
+    #[test]
+    fn test_rollover_to_one() {
+        let rollover = Rollover::ToOne;
+        assert_eq!(rollover.wire_block(0), 0);
+        assert_eq!(rollover.wire_block(65535), 65535);
+        assert_eq!(rollover.wire_block(65536), 1);
+        assert_eq!(rollover.logical_block(1, 65535), 65536);
+        assert_eq!(rollover.logical_block(65535, 65536), 65535);
+        assert_eq!(rollover.logical_block(0, 65536), 0);
+        assert_eq!(rollover.logical_block_from(65535, 0), 65535);
+        assert_eq!(rollover.logical_block_from(1, 65535), 65536);
+    }
+}
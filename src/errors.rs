@@ -19,7 +19,56 @@ pub enum TftprsError {
     #[error("No file")]
     /// The user provided a bad file.
     NoFile,
-    #[error("Error {0} received: {1}")]
-    /// An error was parsed from the remote peer.
-    ErrorResponse(u16, String),
+    #[error("Packet received from an unknown transfer ID")]
+    /// A packet arrived from someone other than the remote peer. An error of the given length was written out, and
+    /// must be sent back to the sender of the packet. The transfer itself continues.
+    UnknownTransferId(usize),
+    #[error("Packet received breaks the protocol")]
+    /// The peer sent a packet that breaks the protocol. An illegal operation error of the given length was written
+    /// out, and must be sent to the peer. The transfer is over.
+    IllegalOperation(usize),
+    #[error("Peer acknowledged an option that was not proposed")]
+    /// The peer acknowledged an option that the host did not propose, or with a value the host cannot accept. An
+    /// option negotiation error of the given length was written out, and must be sent to the peer. The transfer is
+    /// over.
+    OptionNegotiation(usize),
+    #[error("Transfer exceeded its limits")]
+    /// The transfer hit one of the host's limits. An error of the given length was written out, and must be sent to
+    /// the peer. The transfer is over.
+    LimitExceeded(usize),
+    #[error("Buffer of {available} bytes is too small for {needed} bytes")]
+    /// The transmit buffer cannot hold the outgoing message.
+    BufferTooSmall { needed: usize, available: usize },
+    #[error("Transfer timed out")]
+    /// The remote peer did not answer any of the retransmissions of the last message.
+    Timeout,
+    #[error("File access failed: {0}")]
+    /// The file source or sink failed. The transfer is abandoned.
+    Io(String),
+}
+
+/// Problems with the format of a packet, found while parsing or serializing it.
+#[derive(Debug, Error, Clone, Copy, PartialEq, Eq)]
+pub enum PacketError {
+    #[error("Packet is too short")]
+    /// The packet ends before its fixed header does.
+    Truncated,
+    #[error("Unknown opcode {0}")]
+    /// The opcode is not one of those defined.
+    UnknownOpCode(u16),
+    #[error("String is not terminated")]
+    /// A string runs to the end of the packet without a zero byte to end it.
+    MissingTerminator,
+    #[error("String is not valid")]
+    /// A string is not valid UTF-8, or a string to send contains a zero byte.
+    InvalidString,
+    #[error("Option has no name")]
+    /// An option has an empty name.
+    EmptyOptionName,
+    #[error("Packet has trailing bytes")]
+    /// Bytes follow the end of the packet, other than the padding a lenient parse allows.
+    TrailingBytes,
+    #[error("Buffer of {available} bytes is too small for {needed} bytes")]
+    /// The buffer cannot hold the packet.
+    BufferTooSmall { needed: usize, available: usize },
 }
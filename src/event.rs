@@ -0,0 +1,61 @@
+//! Outcomes of handling a message or a timeout
+
+use std::time::Instant;
+
+/// What the host should do after the machine handled a message or a timeout.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    /// Send this many bytes from the transmit buffer to the peer.
+    Transmit(usize),
+    /// The transfer ended successfully, and the machine is idle again, or dallies if the host set a dally duration.
+    /// When the host received the file, the final ack was written out, and this many bytes of it must still be sent.
+    TransferComplete {
+        /// The number of bytes of the file that were sent or received.
+        bytes: u64,
+        /// The number of data blocks in the file.
+        blocks: u64,
+        /// The length of the final message in the transmit buffer, if there is one to send.
+        transmit: Option<usize>,
+    },
+    /// The message was valid but is of no use to the transfer, so nothing was written.
+    Ignored(IgnoreReason),
+    /// The dally period after the host received a file is over, and the machine is idle again. Nothing was written.
+    DallyEnded,
+    /// The peer ended the transfer with an error, and the machine is idle again.
+    PeerError(u16, String),
+    /// Nothing is due until the given time. The host waits for the next message until then, and then calls
+    /// `handle_timeout()`.
+    NeedTimer(Instant),
+}
+
+impl Event {
+    /// The length of the message in the transmit buffer that the host must send, if there is one.
+    pub fn transmit(&self) -> Option<usize> {
+        match self {
+            Event::Transmit(count) => Some(*count),
+            Event::TransferComplete { transmit, .. } => *transmit,
+            _ => None,
+        }
+    }
+}
+
+/// Why a message was ignored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IgnoreReason {
+    /// An ack for a block that was already acknowledged, most likely a duplicate.
+    DuplicateAck,
+    /// A data block that was already received, and does not need to be acknowledged again.
+    DuplicateData,
+    /// An option acknowledgement that was already received, before the first block got through.
+    DuplicateOptionAck,
+    /// A data block ahead of the expected one, after the gap before it was already reported.
+    OutOfOrder,
+    /// An ack for a block that was not sent yet.
+    UnexpectedBlock,
+    /// An error from someone other than the peer, which is never answered.
+    ForeignError,
+    /// No transfer is active.
+    Idle,
+    /// The file was received in full, and only a retransmission of the final block is answered.
+    Dallying,
+}
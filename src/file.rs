@@ -0,0 +1,457 @@
+//! Sources and sinks for the files being transferred
+//!
+//! The machine never needs a whole file in memory. It reads the blocks of a file to send from a `FileSource`, which
+//! must be able to go back to the start of the current window for retransmission, and it writes the blocks of a file
+//! being received to a `FileSink` in order.
+//!
+//! A source or sink may borrow from the host, or be owned by the machine. An owned one, such as an `Arc<[u8]>` or
+//! `Bytes` holding a shared image, or a boxed sink, makes the machine `'static`, so that it can be stored or moved to
+//! another thread along with the data it transfers. `Bytes` is a source with the `bytes` feature.
+
+#[cfg(feature = "bytes")]
+use bytes::Bytes;
+
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::sync::Arc;
+
+/// A file to send.
+pub trait FileSource {
+    /// Reads from the given offset until the buffer is full, and returns the number of bytes read. Fewer bytes are
+    /// read only at the end of the file.
+    fn read_at(&mut self, offset: u64, buffer: &mut [u8]) -> io::Result<usize>;
+
+    /// The length of the file, if it is known in advance.
+    fn len(&self) -> Option<u64> {
+        None
+    }
+
+    /// Indicates whether the file is known to be empty.
+    fn is_empty(&self) -> bool {
+        self.len() == Some(0)
+    }
+
+    /// Tells the source that everything before the given offset was acknowledged, and will not be read again.
+    fn release(&mut self, _offset: u64) {}
+}
+
+/// A file to receive.
+pub trait FileSink {
+    /// Appends data to the file.
+    fn write(&mut self, data: &[u8]) -> io::Result<()>;
+
+    /// Completes the file once the last block arrived.
+    fn finish(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Discards the partial file after the transfer was cancelled or failed, given the number of bytes that were
+    /// written to the sink. By default, the partial data is kept.
+    fn abort(&mut self, _written: u64) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl fmt::Debug for dyn FileSource + '_ {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FileSource")
+            .field("len", &self.len())
+            .finish_non_exhaustive()
+    }
+}
+
+impl fmt::Debug for dyn FileSource + Send + '_ {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FileSource")
+            .field("len", &self.len())
+            .finish_non_exhaustive()
+    }
+}
+
+impl fmt::Debug for dyn FileSink + '_ {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FileSink").finish_non_exhaustive()
+    }
+}
+
+impl fmt::Debug for dyn FileSink + Send + '_ {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FileSink").finish_non_exhaustive()
+    }
+}
+
+impl<T: FileSource + ?Sized> FileSource for &mut T {
+    fn read_at(&mut self, offset: u64, buffer: &mut [u8]) -> io::Result<usize> {
+        (**self).read_at(offset, buffer)
+    }
+
+    fn len(&self) -> Option<u64> {
+        (**self).len()
+    }
+
+    fn release(&mut self, offset: u64) {
+        (**self).release(offset)
+    }
+}
+
+impl<T: FileSink + ?Sized> FileSink for &mut T {
+    fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        (**self).write(data)
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        (**self).finish()
+    }
+
+    fn abort(&mut self, written: u64) -> io::Result<()> {
+        (**self).abort(written)
+    }
+}
+
+impl<T: FileSource + ?Sized> FileSource for Box<T> {
+    fn read_at(&mut self, offset: u64, buffer: &mut [u8]) -> io::Result<usize> {
+        (**self).read_at(offset, buffer)
+    }
+
+    fn len(&self) -> Option<u64> {
+        (**self).len()
+    }
+
+    fn release(&mut self, offset: u64) {
+        (**self).release(offset)
+    }
+}
+
+impl<T: FileSink + ?Sized> FileSink for Box<T> {
+    fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        (**self).write(data)
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        (**self).finish()
+    }
+
+    fn abort(&mut self, written: u64) -> io::Result<()> {
+        (**self).abort(written)
+    }
+}
+
+impl FileSource for &[u8] {
+    fn read_at(&mut self, offset: u64, buffer: &mut [u8]) -> io::Result<usize> {
+        Ok(read_slice_at(self, offset, buffer))
+    }
+
+    fn len(&self) -> Option<u64> {
+        Some(<[u8]>::len(self) as u64)
+    }
+}
+
+impl FileSource for &Vec<u8> {
+    fn read_at(&mut self, offset: u64, buffer: &mut [u8]) -> io::Result<usize> {
+        Ok(read_slice_at(self, offset, buffer))
+    }
+
+    fn len(&self) -> Option<u64> {
+        Some(Vec::len(self) as u64)
+    }
+}
+
+impl FileSource for Vec<u8> {
+    fn read_at(&mut self, offset: u64, buffer: &mut [u8]) -> io::Result<usize> {
+        Ok(read_slice_at(self, offset, buffer))
+    }
+
+    fn len(&self) -> Option<u64> {
+        Some(Vec::len(self) as u64)
+    }
+}
+
+/// Shares one file among any number of transfers without copying it.
+impl FileSource for Arc<[u8]> {
+    fn read_at(&mut self, offset: u64, buffer: &mut [u8]) -> io::Result<usize> {
+        Ok(read_slice_at(self, offset, buffer))
+    }
+
+    fn len(&self) -> Option<u64> {
+        Some(<[u8]>::len(self) as u64)
+    }
+}
+
+/// Shares one file among any number of transfers without copying it.
+#[cfg(feature = "bytes")]
+impl FileSource for Bytes {
+    fn read_at(&mut self, offset: u64, buffer: &mut [u8]) -> io::Result<usize> {
+        Ok(read_slice_at(self, offset, buffer))
+    }
+
+    fn len(&self) -> Option<u64> {
+        Some(Bytes::len(self) as u64)
+    }
+}
+
+impl FileSink for Vec<u8> {
+    fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        self.extend_from_slice(data);
+        Ok(())
+    }
+
+    /// Removes the data that was appended, and keeps anything that was there before.
+    fn abort(&mut self, written: u64) -> io::Result<()> {
+        let written = usize::try_from(written).unwrap_or(usize::MAX);
+        self.truncate(Vec::len(self).saturating_sub(written));
+        Ok(())
+    }
+}
+
+impl FileSource for File {
+    fn read_at(&mut self, offset: u64, buffer: &mut [u8]) -> io::Result<usize> {
+        self.seek(SeekFrom::Start(offset))?;
+        read_full(self, buffer)
+    }
+
+    fn len(&self) -> Option<u64> {
+        self.metadata()
+            .ok()
+            .filter(|metadata| metadata.is_file())
+            .map(|metadata| metadata.len())
+    }
+}
+
+impl FileSink for File {
+    fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        self.write_all(data)
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        self.flush()
+    }
+
+    /// Truncates the file back to where the transfer started writing.
+    fn abort(&mut self, written: u64) -> io::Result<()> {
+        let start = self.stream_position()?.saturating_sub(written);
+        self.set_len(start)?;
+        self.seek(SeekFrom::Start(start))?;
+        Ok(())
+    }
+}
+
+/// Sends from any reader, such as a pipe, whose length is not known in advance. The data from the start of the
+/// current window onward is kept in memory, so that it can be sent again.
+#[derive(Debug)]
+pub struct ReadSource<R> {
+    reader: R,
+    // The data read so far that was not yet released.
+    buffer: Vec<u8>,
+    // The offset in the file of the first byte in the buffer.
+    start: u64,
+    // The reader has no more data.
+    end_of_file: bool,
+}
+
+impl<R: Read> ReadSource<R> {
+    pub fn new(reader: R) -> Self {
+        Self {
+            reader,
+            buffer: Vec::new(),
+            start: 0,
+            end_of_file: false,
+        }
+    }
+
+    /// Gives back the reader.
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+}
+
+impl<R: Read> FileSource for ReadSource<R> {
+    fn read_at(&mut self, offset: u64, buffer: &mut [u8]) -> io::Result<usize> {
+        if offset < self.start {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Data before the offset was already released",
+            ));
+        }
+        // The data kept in memory must be addressable, so an offset too far past the start cannot be read.
+        let Some(end) = usize::try_from(offset - self.start)
+            .ok()
+            .and_then(|skip| skip.checked_add(buffer.len()))
+        else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Offset is too far past the released data",
+            ));
+        };
+        let mut chunk = [0u8; 4096];
+        while self.buffer.len() < end && !self.end_of_file {
+            match self.reader.read(&mut chunk) {
+                Ok(0) => self.end_of_file = true,
+                Ok(count) => self.buffer.extend_from_slice(&chunk[0..count]),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(read_slice_at(&self.buffer, offset - self.start, buffer))
+    }
+
+    fn release(&mut self, offset: u64) {
+        if offset > self.start {
+            let count = usize::try_from(offset - self.start)
+                .unwrap_or(usize::MAX)
+                .min(self.buffer.len());
+            self.buffer.drain(0..count);
+            self.start += count as u64;
+        }
+    }
+}
+
+/// Receives into any writer.
+#[derive(Debug)]
+pub struct WriteSink<W> {
+    writer: W,
+}
+
+impl<W: Write> WriteSink<W> {
+    pub fn new(writer: W) -> Self {
+        Self { writer }
+    }
+
+    /// Gives back the writer.
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+impl<W: Write> FileSink for WriteSink<W> {
+    fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        self.writer.write_all(data)
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// Helper to copy from a slice at the given offset, up to the end of the slice.
+fn read_slice_at(file: &[u8], offset: u64, buffer: &mut [u8]) -> usize {
+    let start = usize::try_from(offset)
+        .unwrap_or(usize::MAX)
+        .min(file.len());
+    let count = buffer.len().min(file.len() - start);
+    buffer[0..count].copy_from_slice(&file[start..start + count]);
+    count
+}
+
+/// Helper to read until the buffer is full or the reader runs out.
+fn read_full(reader: &mut impl Read, buffer: &mut [u8]) -> io::Result<usize> {
+    let mut count = 0;
+    while count < buffer.len() {
+        match reader.read(&mut buffer[count..]) {
+            Ok(0) => break,
+            Ok(read) => count += read,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(count)
+}
+
+mod test {
+    #[cfg(test)]
+    use super::*;
+
+    #[test]
+    fn test_slice_source() {
+        let mut source: &[u8] = b"Hello, world!";
+        let mut buffer = [0u8; 8];
+        assert_eq!(FileSource::len(&source), Some(13));
+        assert_eq!(source.read_at(0, &mut buffer).unwrap(), 8);
+        assert_eq!(&buffer, b"Hello, w");
+        assert_eq!(source.read_at(8, &mut buffer).unwrap(), 5);
+        assert_eq!(&buffer[0..5], b"orld!");
+        assert_eq!(source.read_at(20, &mut buffer).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_vec_sink_abort() {
+        let mut sink = b"Kept".to_vec();
+        FileSink::write(&mut sink, b"Partial").unwrap();
+        sink.abort(7).unwrap();
+        assert_eq!(sink, b"Kept");
+    }
+
+    #[test]
+    fn test_shared_source() {
+        let image: Arc<[u8]> = Arc::from(&b"Hello, world!"[..]);
+        let mut sources: Vec<Box<dyn FileSource + Send>> = vec![Box::new(image.clone())];
+        #[cfg(feature = "bytes")]
+        sources.push(Box::new(Bytes::from_static(b"Hello, world!")));
+        let mut buffer = [0u8; 8];
+        for source in &mut sources {
+            assert_eq!(source.len(), Some(13));
+            assert_eq!(source.read_at(7, &mut buffer).unwrap(), 6);
+            assert_eq!(&buffer[0..6], b"world!");
+        }
+    }
+
+    #[test]
+    fn test_read_source() {
+        // A reader that only gives out a few bytes at a time.
+        struct Trickle<'a>(&'a [u8]);
+        impl Read for Trickle<'_> {
+            fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+                let count = buffer.len().min(self.0.len()).min(3);
+                buffer[0..count].copy_from_slice(&self.0[0..count]);
+                self.0 = &self.0[count..];
+                Ok(count)
+            }
+        }
+
+        let mut source = ReadSource::new(Trickle(b"Hello, world!"));
+        let mut buffer = [0u8; 8];
+        assert_eq!(source.len(), None);
+        assert_eq!(source.read_at(0, &mut buffer).unwrap(), 8);
+        assert_eq!(&buffer, b"Hello, w");
+        // Reading again from a kept offset gives the same data.
+        assert_eq!(source.read_at(4, &mut buffer).unwrap(), 8);
+        assert_eq!(&buffer, b"o, world");
+        source.release(8);
+        assert!(source.read_at(4, &mut buffer).is_err());
+        assert_eq!(source.read_at(8, &mut buffer).unwrap(), 5);
+        assert_eq!(&buffer[0..5], b"orld!");
+        // An offset whose end cannot be addressed in memory is an error rather than a wrapped read.
+        assert_eq!(
+            source.read_at(u64::MAX, &mut [0u8; 16]).unwrap_err().kind(),
+            io::ErrorKind::InvalidInput
+        );
+        // Releasing past the end only drops what was read.
+        source.release(u64::MAX);
+        assert_eq!(source.read_at(13, &mut buffer).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_file_source_and_sink() {
+        let path = std::env::temp_dir().join(format!("tftp-rs-file-{}", std::process::id()));
+        {
+            let mut sink = File::create(&path).unwrap();
+            FileSink::write(&mut sink, b"Hello, ").unwrap();
+            FileSink::write(&mut sink, b"world!").unwrap();
+            sink.finish().unwrap();
+        }
+        {
+            let mut source = File::open(&path).unwrap();
+            let mut buffer = [0u8; 8];
+            assert_eq!(FileSource::len(&source), Some(13));
+            assert_eq!(source.read_at(7, &mut buffer).unwrap(), 6);
+            assert_eq!(&buffer[0..6], b"world!");
+        }
+        {
+            let mut sink = File::options().append(true).open(&path).unwrap();
+            FileSink::write(&mut sink, b" Goodbye").unwrap();
+            sink.abort(8).unwrap();
+        }
+        assert_eq!(std::fs::read(&path).unwrap(), b"Hello, world!");
+        std::fs::remove_file(&path).unwrap();
+    }
+}
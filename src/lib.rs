@@ -1,4 +1,5 @@
-//! This library provides an implementation of the Trivial File Transfer Protocol per RFC 1350 Rev 2.
+//! This library provides an implementation of the Trivial File Transfer Protocol per RFC 1350 Rev 2,
+//! with the option extension of RFC 2347.
 //! The host can use the interface in one of two ways:
 //!
 //! * Listening for requests
@@ -10,32 +11,75 @@
 //! Once a transfer is active, the messages are processed with the same method regardless whether
 //! the host or the remote peer initiated the transfer.
 //!
+//! The `roles` module wraps the machine in a type for each role, such as a client downloading a file, that only
+//! offers the methods that are valid in that role.
+//!
 
+pub mod config;
+pub mod congestion;
 pub mod constants;
 pub mod errors;
+pub mod event;
+pub mod file;
+pub mod limits;
 pub mod machine;
+pub(crate) mod netascii;
+pub mod options;
+pub mod packet;
+pub mod roles;
 pub(crate) mod serial;
+pub mod state;
+pub mod timer;
 
 mod tests {
+    #[cfg(test)]
+    use crate::config::*;
+    #[cfg(test)]
+    use crate::congestion::*;
     #[cfg(test)]
     use crate::constants::*;
     #[cfg(test)]
     use crate::errors::TftprsError;
     #[cfg(test)]
+    use crate::event::*;
+    #[cfg(test)]
+    use crate::file::*;
+    #[cfg(test)]
+    use crate::limits::*;
+    #[cfg(test)]
     use crate::machine::*;
     #[cfg(test)]
+    use crate::options::*;
+    #[cfg(test)]
+    use crate::roles::*;
+    #[cfg(test)]
     use crate::serial::*;
+    #[cfg(test)]
+    use crate::state::*;
+    #[cfg(test)]
+    use std::collections::HashMap;
+    #[cfg(test)]
+    use std::net::SocketAddr;
+    #[cfg(test)]
+    use std::sync::Arc;
+    #[cfg(test)]
+    use std::time::{Duration, Instant};
+
+    #[cfg(test)]
+    fn peer() -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], 49152))
+    }
 
     #[test]
     fn test_write_request() {
+        let my_file: Vec<u8> = [0x5A; 1024].to_vec();
         let mut machine = Machine::new();
         assert!(!machine.is_busy());
-        let mut my_file: Vec<u8> = [0x5A; 1024].to_vec();
         let mut tx = [0u8; MAX_PACKET_SIZE];
         let mut rx = [0u8; MAX_PACKET_SIZE];
         // Send request
         let count = machine
-            .request_send_file(String::from("ABCDE"), &mut my_file, &mut tx)
+            .request_send_file(Instant::now(), String::from("ABCDE"), &my_file, &mut tx)
             .expect("send file");
         assert_eq!(count, 14);
         assert_eq!(tx[1], OpCode::WriteRequest as u8);
@@ -45,9 +89,14 @@ mod tests {
         // Process ack
         let ack = Ack::new(0);
         let count = ack.serialize(&mut rx);
-        let count = machine.process(&rx, count, &mut tx).unwrap();
+        let event = machine
+            .process(Instant::now(), peer(), &rx[0..count], &mut tx)
+            .unwrap();
         // Send out next packet
-        assert_eq!(count, MAX_PACKET_SIZE);
+        assert_eq!(
+            event,
+            Event::Transmit(DEFAULT_BLOCK_SIZE + FIXED_DATA_BYTES)
+        );
         assert_eq!(tx[1], OpCode::Data as u8);
     }
 
@@ -65,7 +114,7 @@ mod tests {
             let mut machine = Machine::new();
             // Send request
             let count = machine
-                .request_receive_file(String::from("ABCDE"), &mut my_file, &mut tx)
+                .request_receive_file(Instant::now(), String::from("ABCDE"), &mut my_file, &mut tx)
                 .expect("receive file");
             assert_eq!(count, 14);
             assert_eq!(tx[1], OpCode::ReadRequest as u8);
@@ -73,36 +122,46 @@ mod tests {
             // Server's first block message
             let mut incoming_data = [0x5A; 1024].to_vec();
             // Disambiguate the two blocks
-            incoming_data[MAX_DATA_SIZE] = 0xA5;
+            incoming_data[DEFAULT_BLOCK_SIZE] = 0xA5;
 
             // Process first block
-            let data = Data::new(1, &incoming_data);
-            let message_size = data.unwrap().serialize(&mut rx);
-            assert_eq!(message_size, MAX_PACKET_SIZE);
-            let count = machine.process(&rx, message_size, &mut tx).unwrap();
+            let data = Data::new(
+                1,
+                file_block(&incoming_data, 1, DEFAULT_BLOCK_SIZE).unwrap(),
+            );
+            let message_size = data.serialize(&mut rx);
+            assert_eq!(message_size, DEFAULT_BLOCK_SIZE + FIXED_DATA_BYTES);
+            let event = machine
+                .process(Instant::now(), peer(), &rx[0..message_size], &mut tx)
+                .unwrap();
 
             // Send ack
-            assert_eq!(count, 4);
+            assert_eq!(event, Event::Transmit(4));
             assert_eq!(tx[1], OpCode::Acknowledgement as u8);
             assert_eq!(tx[3], 1);
 
             // Process second block
-            let data = Data::new(2, &incoming_data);
-            let message_size = data.unwrap().serialize(&mut rx);
-            assert_eq!(message_size, MAX_PACKET_SIZE);
-            let count = machine.process(&rx, MAX_PACKET_SIZE, &mut tx).unwrap();
+            let data = Data::new(
+                2,
+                file_block(&incoming_data, 2, DEFAULT_BLOCK_SIZE).unwrap(),
+            );
+            let message_size = data.serialize(&mut rx);
+            assert_eq!(message_size, DEFAULT_BLOCK_SIZE + FIXED_DATA_BYTES);
+            let event = machine
+                .process(Instant::now(), peer(), &rx[0..message_size], &mut tx)
+                .unwrap();
 
             // Send ack
-            assert_eq!(count, 4);
+            assert_eq!(event, Event::Transmit(4));
             assert_eq!(tx[1], OpCode::Acknowledgement as u8);
             assert_eq!(tx[3], 2);
         }
 
         // Verify data written
-        assert_eq!(my_file.get(0).unwrap(), &0x5A);
-        assert_eq!(my_file.get(MAX_DATA_SIZE - 1).unwrap(), &0x5A);
-        assert_eq!(my_file.get(MAX_DATA_SIZE).unwrap(), &0xA5);
-        assert_eq!(my_file.len(), MAX_DATA_SIZE * 2);
+        assert_eq!(my_file.first().unwrap(), &0x5A);
+        assert_eq!(my_file.get(DEFAULT_BLOCK_SIZE - 1).unwrap(), &0x5A);
+        assert_eq!(my_file.get(DEFAULT_BLOCK_SIZE).unwrap(), &0xA5);
+        assert_eq!(my_file.len(), DEFAULT_BLOCK_SIZE * 2);
     }
 
     #[test]
@@ -118,23 +177,30 @@ mod tests {
             let mut machine = Machine::new();
             // Send request
             let request = Request::new(TransferType::Read, Mode::Binary, String::from("ABCDE"));
-            request.unwrap().serialize(&mut rx);
-            let filename = machine.listen_for_request(&rx).unwrap();
+            let count = request.unwrap().serialize(&mut rx);
+            let filename = machine.listen_for_request(peer(), &rx[0..count]).unwrap();
             assert_eq!(machine.transfer_type().unwrap(), TransferType::Write);
             assert!(machine.is_busy());
             assert_eq!(machine.mode(), Mode::Binary);
             assert_eq!(filename, String::from("ABCDE"));
-            let count = machine.reply_send_file(&my_file, &mut tx).unwrap();
+            let count = machine
+                .reply_send_file(Instant::now(), &my_file, &mut tx)
+                .unwrap();
             // Send out next packet
-            assert_eq!(count, MAX_PACKET_SIZE);
+            assert_eq!(count, DEFAULT_BLOCK_SIZE + FIXED_DATA_BYTES);
             assert_eq!(tx[1], OpCode::Data as u8);
             assert_eq!(tx[3], 1);
             // Process ack
             let ack = Ack::new(1);
             let count = ack.serialize(&mut rx);
-            let count = machine.process(&rx, count, &mut tx).unwrap();
+            let event = machine
+                .process(Instant::now(), peer(), &rx[0..count], &mut tx)
+                .unwrap();
             // Send out next packet
-            assert_eq!(count, MAX_PACKET_SIZE);
+            assert_eq!(
+                event,
+                Event::Transmit(DEFAULT_BLOCK_SIZE + FIXED_DATA_BYTES)
+            );
             assert_eq!(tx[1], OpCode::Data as u8);
             assert_eq!(tx[3], 2);
         }
@@ -152,13 +218,15 @@ mod tests {
         {
             let mut machine = Machine::new();
             let request = Request::new(TransferType::Write, Mode::Text, String::from("ABCDE"));
-            request.unwrap().serialize(&mut rx);
-            let filename = machine.listen_for_request(&rx).unwrap();
+            let count = request.unwrap().serialize(&mut rx);
+            let filename = machine.listen_for_request(peer(), &rx[0..count]).unwrap();
             assert_eq!(machine.transfer_type().unwrap(), TransferType::Read);
             assert!(machine.is_busy());
             assert_eq!(machine.mode(), Mode::Text);
             assert_eq!(filename, String::from("ABCDE"));
-            let count = machine.reply_receive_file(&mut my_file, &mut tx).unwrap();
+            let count = machine
+                .reply_receive_file(Instant::now(), &mut my_file, &mut tx)
+                .unwrap();
 
             // Send out ack
             assert_eq!(count, 4);
@@ -167,16 +235,26 @@ mod tests {
 
             // Process first block
             let incoming_data = String::from("Hello, world!").to_string().into_bytes();
-            let data = Data::new(1, &incoming_data);
-            let message_size = data.unwrap().serialize(&mut rx);
-            let count = machine.process(&rx, message_size, &mut tx).unwrap();
+            let data = Data::new(
+                1,
+                file_block(&incoming_data, 1, DEFAULT_BLOCK_SIZE).unwrap(),
+            );
+            let message_size = data.serialize(&mut rx);
+            let event = machine
+                .process(Instant::now(), peer(), &rx[0..message_size], &mut tx)
+                .unwrap();
 
-            // Send out ack
-            assert_eq!(count, 4);
+            // Send out ack, and the file is complete
+            assert_eq!(
+                event,
+                Event::TransferComplete {
+                    bytes: 13,
+                    blocks: 1,
+                    transmit: Some(4)
+                }
+            );
             assert_eq!(tx[1], OpCode::Acknowledgement as u8);
             assert_eq!(tx[3], 1);
-
-            // File is complete
             assert!(!machine.is_busy());
             assert_eq!(machine.transfer_type(), None);
         }
@@ -188,28 +266,30 @@ mod tests {
 
     #[test]
     fn test_receive_error_response_on_write_request() {
+        let my_file: Vec<u8> = [0x5A; 1024].to_vec();
         let mut machine = Machine::new();
         assert!(!machine.is_busy());
-        let mut my_file: Vec<u8> = [0x5A; 1024].to_vec();
         let mut tx = [0u8; MAX_PACKET_SIZE];
         let mut rx = [0u8; MAX_PACKET_SIZE];
         // Send request
         let _ = machine
-            .request_send_file(String::from("ABCDE"), &mut my_file, &mut tx)
+            .request_send_file(Instant::now(), String::from("ABCDE"), &my_file, &mut tx)
             .expect("send file");
         assert!(machine.is_busy());
         // Process error
         let error_received =
             ErrorResponse::new(ErrorCode::FileNotFound, String::from("File not found"));
         let count = error_received.serialize(&mut rx);
-        let e = machine.process(&rx, count, &mut tx).err().unwrap();
-        match e {
-            TftprsError::ErrorResponse(code, message) => {
+        let event = machine
+            .process(Instant::now(), peer(), &rx[0..count], &mut tx)
+            .unwrap();
+        match event {
+            Event::PeerError(code, message) => {
                 assert_eq!(code, ErrorCode::FileNotFound as u16);
                 assert_eq!(message, String::from("File not found"));
             }
             _ => {
-                panic!("Unexpected error: {:?}", e);
+                panic!("Unexpected event: {:?}", event);
             }
         }
         assert!(!machine.is_busy());
@@ -223,8 +303,8 @@ mod tests {
         let mut machine = Machine::new();
         // Send request
         let request = Request::new(TransferType::Read, Mode::Binary, String::from("ABCDE"));
-        request.unwrap().serialize(&mut rx);
-        let _ = machine.listen_for_request(&rx);
+        let count = request.unwrap().serialize(&mut rx);
+        let _ = machine.listen_for_request(peer(), &rx[0..count]);
 
         // Decide that there is no such file.
         machine
@@ -237,4 +317,2110 @@ mod tests {
         assert!(!machine.is_busy());
         assert_eq!(machine.transfer_type(), None);
     }
+
+    #[test]
+    fn test_listen_for_request_with_options() {
+        let my_file: Vec<u8> = [0x5A; 1024].to_vec();
+        let mut tx = [0u8; MAX_PACKET_SIZE];
+        let mut rx = [0u8; MAX_PACKET_SIZE];
+
+        let mut machine = Machine::new();
+        let request = Request::new(TransferType::Read, Mode::Binary, String::from("ABCDE"))
+            .unwrap()
+            .with_options(vec![
+                TransferOption::new("colour", "blue"),
+                TransferOption::new("flavour", "mint"),
+            ])
+            .unwrap();
+        let count = request.serialize(&mut rx);
+        machine.listen_for_request(peer(), &rx[0..count]).unwrap();
+        assert_eq!(machine.requested_options().len(), 2);
+        assert!(machine.requested_options()[1].is("FLAVOUR"));
+
+        // Clamp one option and reject the other by leaving it out.
+        machine
+            .accept_option(TransferOption::new("colour", "green"))
+            .unwrap();
+        assert_eq!(
+            machine.accept_option(TransferOption::new("size", "1")),
+            Err(TftprsError::BadRequestAttempted)
+        );
+        let count = machine
+            .reply_send_file(Instant::now(), &my_file, &mut tx)
+            .unwrap();
+        assert_eq!(&tx[0..count], b"\x00\x06colour\x00green\x00");
+
+        // The peer acknowledges the OACK with block 0, and the transfer begins.
+        let count = Ack::new(0).serialize(&mut rx);
+        let event = machine
+            .process(Instant::now(), peer(), &rx[0..count], &mut tx)
+            .unwrap();
+        assert_eq!(
+            event,
+            Event::Transmit(DEFAULT_BLOCK_SIZE + FIXED_DATA_BYTES)
+        );
+        assert_eq!(tx[1], OpCode::Data as u8);
+        assert_eq!(tx[3], 1);
+    }
+
+    #[test]
+    fn test_request_with_option_ack() {
+        let my_file: Vec<u8> = [0x5A; 1024].to_vec();
+        let mut tx = [0u8; MAX_PACKET_SIZE];
+        let mut rx = [0u8; MAX_PACKET_SIZE];
+
+        let mut machine = Machine::new();
+        machine
+            .set_options(vec![TransferOption::new("colour", "blue")])
+            .unwrap();
+        let count = machine
+            .request_send_file(Instant::now(), String::from("ABCDE"), &my_file, &mut tx)
+            .unwrap();
+        assert_eq!(
+            &tx[0..count],
+            b"\x00\x02ABCDE\x00OCTET\x00colour\x00blue\x00"
+        );
+
+        // The OACK takes the place of the ack at block 0.
+        let count = OptionAck::new(vec![TransferOption::new("colour", "blue")]).serialize(&mut rx);
+        let event = machine
+            .process(Instant::now(), peer(), &rx[0..count], &mut tx)
+            .unwrap();
+        assert_eq!(
+            event,
+            Event::Transmit(DEFAULT_BLOCK_SIZE + FIXED_DATA_BYTES)
+        );
+        assert_eq!(tx[1], OpCode::Data as u8);
+        assert_eq!(tx[3], 1);
+        assert_eq!(machine.negotiated_options().len(), 1);
+
+        // The OACK again means the peer missed block 1, which the timer will send again.
+        let count = OptionAck::new(vec![TransferOption::new("colour", "blue")]).serialize(&mut rx);
+        assert_eq!(
+            machine.process(Instant::now(), peer(), &rx[0..count], &mut tx),
+            Ok(Event::Ignored(IgnoreReason::DuplicateOptionAck))
+        );
+
+        // Once block 1 is acknowledged, another OACK is out of place.
+        let count = Ack::new(1).serialize(&mut rx);
+        machine
+            .process(Instant::now(), peer(), &rx[0..count], &mut tx)
+            .unwrap();
+        let count = OptionAck::new(Vec::new()).serialize(&mut rx);
+        assert_eq!(
+            machine.process(Instant::now(), peer(), &rx[0..count], &mut tx),
+            Err(TftprsError::BadPacketReceived)
+        );
+    }
+
+    #[test]
+    fn test_duplicate_option_ack() {
+        let mut my_file: Vec<u8> = Vec::new();
+        let mut tx = [0u8; MAX_PACKET_SIZE];
+        let mut rx = [0u8; MAX_PACKET_SIZE];
+
+        {
+            let mut machine = Machine::new();
+            machine.set_reply_to_violations(true).unwrap();
+            machine
+                .set_options(vec![TransferOption::block_size(1024)])
+                .unwrap();
+            machine
+                .request_receive_file(Instant::now(), String::from("ABCDE"), &mut my_file, &mut tx)
+                .unwrap();
+            let count = OptionAck::new(vec![TransferOption::block_size(1024)]).serialize(&mut rx);
+            assert_eq!(
+                machine.process(Instant::now(), peer(), &rx[0..count], &mut tx),
+                Ok(Event::Transmit(4))
+            );
+            assert_eq!(&tx[0..4], b"\x00\x04\x00\x00");
+
+            // The ack at block 0 was lost, so the peer sends the OACK again, and gets the ack again.
+            tx.fill(0);
+            assert_eq!(
+                machine.process(Instant::now(), peer(), &rx[0..count], &mut tx),
+                Ok(Event::Transmit(4))
+            );
+            assert_eq!(&tx[0..4], b"\x00\x04\x00\x00");
+            assert!(machine.is_busy());
+
+            let count = Data::new(1, b"Hello").serialize(&mut rx);
+            let event = machine
+                .process(Instant::now(), peer(), &rx[0..count], &mut tx)
+                .unwrap();
+            assert_eq!(event.transmit(), Some(4));
+        }
+        assert_eq!(my_file, b"Hello");
+    }
+
+    #[test]
+    fn test_reject_unrequested_option_ack() {
+        let mut my_file: Vec<u8> = Vec::new();
+        let mut tx = [0u8; MAX_PACKET_SIZE];
+        let mut rx = [0u8; MAX_PACKET_SIZE];
+
+        let mut machine = Machine::new();
+        machine
+            .set_options(vec![TransferOption::new("colour", "blue")])
+            .unwrap();
+        machine
+            .request_receive_file(Instant::now(), String::from("ABCDE"), &mut my_file, &mut tx)
+            .unwrap();
+        let count = OptionAck::new(vec![TransferOption::new("size", "1")]).serialize(&mut rx);
+        assert_eq!(
+            machine.process(Instant::now(), peer(), &rx[0..count], &mut tx),
+            Err(TftprsError::OptionNegotiation(29))
+        );
+        assert_eq!(tx[1], OpCode::Error as u8);
+        assert_eq!(tx[3], ErrorCode::OptionNegotiation as u8);
+        assert!(!machine.is_busy());
+    }
+
+    #[test]
+    fn test_negotiated_block_size() {
+        let mut my_file: Vec<u8> = Vec::new();
+        let mut tx = [0u8; MAX_PACKET_SIZE];
+        let mut rx = [0u8; MAX_PACKET_SIZE];
+        let mut incoming_data = [0x5A; 2000].to_vec();
+        incoming_data[1428] = 0xA5;
+
+        {
+            let mut machine = Machine::new();
+            machine
+                .set_options(vec![TransferOption::block_size(8192)])
+                .unwrap();
+            machine
+                .request_receive_file(Instant::now(), String::from("ABCDE"), &mut my_file, &mut tx)
+                .unwrap();
+
+            // The server clamps the block size.
+            let count = OptionAck::new(vec![TransferOption::block_size(1428)]).serialize(&mut rx);
+            let event = machine
+                .process(Instant::now(), peer(), &rx[0..count], &mut tx)
+                .unwrap();
+            assert_eq!(event, Event::Transmit(4));
+            assert_eq!(tx[1], OpCode::Acknowledgement as u8);
+            assert_eq!(tx[3], 0);
+            assert_eq!(machine.block_size(), 1428);
+
+            // A full block is not the end of the file.
+            let message_size =
+                Data::new(1, file_block(&incoming_data, 1, 1428).unwrap()).serialize(&mut rx);
+            assert_eq!(message_size, 1428 + FIXED_DATA_BYTES);
+            machine
+                .process(Instant::now(), peer(), &rx[0..message_size], &mut tx)
+                .unwrap();
+            assert!(machine.is_busy());
+
+            // A short block is.
+            let message_size =
+                Data::new(2, file_block(&incoming_data, 2, 1428).unwrap()).serialize(&mut rx);
+            let event = machine
+                .process(Instant::now(), peer(), &rx[0..message_size], &mut tx)
+                .unwrap();
+            assert_eq!(
+                event,
+                Event::TransferComplete {
+                    bytes: 2000,
+                    blocks: 2,
+                    transmit: Some(4)
+                }
+            );
+            assert_eq!(tx[3], 2);
+            assert!(!machine.is_busy());
+        }
+        assert_eq!(my_file, incoming_data);
+    }
+
+    #[test]
+    fn test_reject_larger_block_size() {
+        let my_file: Vec<u8> = [0x5A; 1024].to_vec();
+        let mut tx = [0u8; MAX_PACKET_SIZE];
+        let mut rx = [0u8; MAX_PACKET_SIZE];
+
+        let mut machine = Machine::new();
+        let request = Request::new(TransferType::Read, Mode::Binary, String::from("ABCDE"))
+            .unwrap()
+            .with_options(vec![TransferOption::block_size(1024)])
+            .unwrap();
+        let count = request.serialize(&mut rx);
+        machine.listen_for_request(peer(), &rx[0..count]).unwrap();
+        assert_eq!(
+            machine.accept_option(TransferOption::block_size(2048)),
+            Err(TftprsError::BadRequestAttempted)
+        );
+        machine
+            .accept_option(TransferOption::block_size(600))
+            .unwrap();
+        machine
+            .reply_send_file(Instant::now(), &my_file, &mut tx)
+            .unwrap();
+        let count = Ack::new(0).serialize(&mut rx);
+        let event = machine
+            .process(Instant::now(), peer(), &rx[0..count], &mut tx)
+            .unwrap();
+        assert_eq!(event, Event::Transmit(600 + FIXED_DATA_BYTES));
+    }
+
+    #[test]
+    fn test_timeout_and_transfer_size_options() {
+        let my_file: Vec<u8> = [0x5A; 1024].to_vec();
+        let mut tx = [0u8; MAX_PACKET_SIZE];
+        let mut rx = [0u8; MAX_PACKET_SIZE];
+
+        let mut machine = Machine::new();
+        let request = Request::new(TransferType::Read, Mode::Binary, String::from("ABCDE"))
+            .unwrap()
+            .with_options(vec![
+                TransferOption::transfer_size(0),
+                TransferOption::timeout(5),
+            ])
+            .unwrap();
+        let count = request.serialize(&mut rx);
+        machine.listen_for_request(peer(), &rx[0..count]).unwrap();
+        assert_eq!(
+            machine.accept_option(TransferOption::timeout(1)),
+            Err(TftprsError::BadRequestAttempted)
+        );
+        machine
+            .accept_option(TransferOption::transfer_size(0))
+            .unwrap();
+        machine.accept_option(TransferOption::timeout(5)).unwrap();
+
+        // The machine fills in the size of the file.
+        let count = machine
+            .reply_send_file(Instant::now(), &my_file, &mut tx)
+            .unwrap();
+        assert_eq!(&tx[0..count], b"\x00\x06tsize\x001024\x00timeout\x005\x00");
+        assert_eq!(machine.timeout(), Some(Duration::from_secs(5)));
+        assert_eq!(machine.transfer_size(), Some(1024));
+    }
+
+    #[test]
+    fn test_write_request_declares_transfer_size() {
+        let my_file: Vec<u8> = [0x5A; 1024].to_vec();
+        let mut tx = [0u8; MAX_PACKET_SIZE];
+
+        let mut machine = Machine::new();
+        machine
+            .set_options(vec![TransferOption::transfer_size(0)])
+            .unwrap();
+        let count = machine
+            .request_send_file(Instant::now(), String::from("ABCDE"), &my_file, &mut tx)
+            .unwrap();
+        assert_eq!(
+            &tx[0..count],
+            b"\x00\x02ABCDE\x00OCTET\x00tsize\x001024\x00"
+        );
+    }
+
+    #[test]
+    fn test_reject_large_upload() {
+        let mut my_file: Vec<u8> = Vec::new();
+        let mut tx = [0u8; MAX_PACKET_SIZE];
+        let mut rx = [0u8; MAX_PACKET_SIZE];
+
+        let mut machine = Machine::new();
+        machine
+            .set_limits(TransferLimits {
+                max_bytes: Some(4096),
+                ..Default::default()
+            })
+            .unwrap();
+        let request = Request::new(TransferType::Write, Mode::Binary, String::from("ABCDE"))
+            .unwrap()
+            .with_options(vec![TransferOption::transfer_size(10000)])
+            .unwrap();
+        let count = request.serialize(&mut rx);
+        machine.listen_for_request(peer(), &rx[0..count]).unwrap();
+        assert_eq!(machine.transfer_size(), Some(10000));
+        assert_eq!(
+            machine.reply_receive_file(Instant::now(), &mut my_file, &mut tx),
+            Err(TftprsError::LimitExceeded(43))
+        );
+        assert_eq!(tx[1], OpCode::Error as u8);
+        assert_eq!(tx[3], ErrorCode::DiskFull as u8);
+        assert!(!machine.is_busy());
+
+        // Mail is refused the same way.
+        let request = Request::new(TransferType::Write, Mode::Mail, String::from("postmaster"))
+            .unwrap()
+            .with_options(vec![TransferOption::transfer_size(10000)])
+            .unwrap();
+        let count = request.serialize(&mut rx);
+        machine.listen_for_request(peer(), &rx[0..count]).unwrap();
+        assert_eq!(
+            machine.reply_receive_mail(Instant::now(), |_, _| {}, &mut tx),
+            Err(TftprsError::LimitExceeded(43))
+        );
+        assert!(!machine.is_busy());
+    }
+
+    #[test]
+    fn test_refuse_large_download() {
+        let mut my_file: Vec<u8> = Vec::new();
+        let mut tx = [0u8; MAX_PACKET_SIZE];
+        let mut rx = [0u8; MAX_PACKET_SIZE];
+
+        let mut machine = Machine::new();
+        machine
+            .set_limits(TransferLimits {
+                max_bytes: Some(4096),
+                ..Default::default()
+            })
+            .unwrap();
+        machine
+            .set_options(vec![TransferOption::transfer_size(0)])
+            .unwrap();
+        machine
+            .request_receive_file(Instant::now(), String::from("ABCDE"), &mut my_file, &mut tx)
+            .unwrap();
+        let count = OptionAck::new(vec![TransferOption::transfer_size(10000)]).serialize(&mut rx);
+        assert_eq!(
+            machine.process(Instant::now(), peer(), &rx[0..count], &mut tx),
+            Err(TftprsError::LimitExceeded(43))
+        );
+        assert_eq!(tx[1], OpCode::Error as u8);
+        assert_eq!(tx[3], ErrorCode::DiskFull as u8);
+        assert!(!machine.is_busy());
+    }
+
+    #[test]
+    fn test_send_window() {
+        // Ten blocks and a short one
+        let my_file: Vec<u8> = [0x5A; 84].to_vec();
+        let mut tx = [0u8; MAX_PACKET_SIZE];
+        let mut rx = [0u8; MAX_PACKET_SIZE];
+
+        let mut machine = Machine::new();
+        let request = Request::new(TransferType::Read, Mode::Binary, String::from("ABCDE"))
+            .unwrap()
+            .with_options(vec![
+                TransferOption::block_size(8),
+                TransferOption::window_size(4),
+            ])
+            .unwrap();
+        let count = request.serialize(&mut rx);
+        machine.listen_for_request(peer(), &rx[0..count]).unwrap();
+        machine
+            .accept_option(TransferOption::block_size(8))
+            .unwrap();
+        machine
+            .accept_option(TransferOption::window_size(4))
+            .unwrap();
+        machine
+            .reply_send_file(Instant::now(), &my_file, &mut tx)
+            .unwrap();
+        assert_eq!(machine.window_size(), 4);
+        // Nothing more to send until the OACK is acknowledged.
+        assert_eq!(machine.poll_transmit(&mut tx), Ok(None));
+
+        // The whole window goes out.
+        let count = Ack::new(0).serialize(&mut rx);
+        machine
+            .process(Instant::now(), peer(), &rx[0..count], &mut tx)
+            .unwrap();
+        assert_eq!(tx[3], 1);
+        for block in 2..=4 {
+            assert_eq!(machine.poll_transmit(&mut tx), Ok(Some(12)));
+            assert_eq!(tx[3], block);
+        }
+        assert_eq!(machine.poll_transmit(&mut tx), Ok(None));
+
+        // A partial ack restarts the window after the acknowledged block.
+        let count = Ack::new(2).serialize(&mut rx);
+        machine
+            .process(Instant::now(), peer(), &rx[0..count], &mut tx)
+            .unwrap();
+        assert_eq!(tx[3], 3);
+        for block in 4..=6 {
+            assert_eq!(machine.poll_transmit(&mut tx), Ok(Some(12)));
+            assert_eq!(tx[3], block);
+        }
+        assert_eq!(machine.poll_transmit(&mut tx), Ok(None));
+
+        // An ack from before the window is stale.
+        let count = Ack::new(1).serialize(&mut rx);
+        assert_eq!(
+            machine.process(Instant::now(), peer(), &rx[0..count], &mut tx),
+            Ok(Event::Ignored(IgnoreReason::DuplicateAck))
+        );
+        assert_eq!(machine.poll_transmit(&mut tx), Ok(None));
+
+        // The final window is cut short by the end of the file.
+        let count = Ack::new(6).serialize(&mut rx);
+        machine
+            .process(Instant::now(), peer(), &rx[0..count], &mut tx)
+            .unwrap();
+        assert_eq!(tx[3], 7);
+        for block in 8..=10 {
+            assert_eq!(machine.poll_transmit(&mut tx), Ok(Some(12)));
+            assert_eq!(tx[3], block);
+        }
+        let count = Ack::new(10).serialize(&mut rx);
+        assert_eq!(
+            machine.process(Instant::now(), peer(), &rx[0..count], &mut tx),
+            Ok(Event::Transmit(8))
+        );
+        assert_eq!(tx[3], 11);
+        assert_eq!(machine.poll_transmit(&mut tx), Ok(None));
+
+        let count = Ack::new(11).serialize(&mut rx);
+        assert_eq!(
+            machine.process(Instant::now(), peer(), &rx[0..count], &mut tx),
+            Ok(Event::TransferComplete {
+                bytes: 84,
+                blocks: 11,
+                transmit: None
+            })
+        );
+        assert!(!machine.is_busy());
+    }
+
+    #[test]
+    fn test_receive_window() {
+        let mut my_file: Vec<u8> = Vec::new();
+        let mut tx = [0u8; MAX_PACKET_SIZE];
+        let mut rx = [0u8; MAX_PACKET_SIZE];
+        let incoming_data: Vec<u8> = (0..36).collect();
+
+        {
+            let mut machine = Machine::new();
+            machine
+                .set_options(vec![
+                    TransferOption::block_size(8),
+                    TransferOption::window_size(2),
+                ])
+                .unwrap();
+            machine
+                .request_receive_file(Instant::now(), String::from("ABCDE"), &mut my_file, &mut tx)
+                .unwrap();
+            let count = OptionAck::new(vec![
+                TransferOption::block_size(8),
+                TransferOption::window_size(2),
+            ])
+            .serialize(&mut rx);
+            machine
+                .process(Instant::now(), peer(), &rx[0..count], &mut tx)
+                .unwrap();
+
+            let mut receive = |block: u16| {
+                let count = Data::new(block, file_block(&incoming_data, block.into(), 8).unwrap())
+                    .serialize(&mut rx);
+                machine
+                    .process(Instant::now(), peer(), &rx[0..count], &mut tx)
+                    .map(|event| match event {
+                        Event::Transmit(_) | Event::TransferComplete { .. } => {
+                            assert_eq!(tx[1], OpCode::Acknowledgement as u8);
+                            Some(tx[3])
+                        }
+                        _ => None,
+                    })
+            };
+            // Only the end of a window is acknowledged.
+            assert_eq!(receive(1), Ok(None));
+            assert_eq!(receive(2), Ok(Some(2)));
+            // Block 3 is lost. The last block received in order is acknowledged only once.
+            assert_eq!(receive(4), Ok(Some(2)));
+            assert_eq!(receive(5), Ok(None));
+            // The sender starts again from block 3.
+            assert_eq!(receive(3), Ok(None));
+            assert_eq!(receive(4), Ok(Some(4)));
+            // The final block is acknowledged right away.
+            assert_eq!(receive(5), Ok(Some(5)));
+        }
+        assert_eq!(my_file, incoming_data);
+    }
+
+    #[test]
+    fn test_block_number_rollover() {
+        // More blocks than the block field can number
+        let mut my_file: Vec<u8> = vec![0x5A; 8 * 65536 + 3];
+        my_file[8 * 65535] = 0xA5;
+        let mut tx = [0u8; MAX_PACKET_SIZE];
+        let mut rx = [0u8; MAX_PACKET_SIZE];
+
+        let mut machine = Machine::new();
+        let request = Request::new(TransferType::Read, Mode::Binary, String::from("ABCDE"))
+            .unwrap()
+            .with_options(vec![
+                TransferOption::block_size(8),
+                TransferOption::window_size(u16::MAX),
+                TransferOption::rollover(Rollover::ToOne),
+            ])
+            .unwrap();
+        let count = request.serialize(&mut rx);
+        machine.listen_for_request(peer(), &rx[0..count]).unwrap();
+        for option in machine.requested_options().to_vec() {
+            machine.accept_option(option).unwrap();
+        }
+        machine
+            .reply_send_file(Instant::now(), &my_file, &mut tx)
+            .unwrap();
+        assert_eq!(machine.rollover(), Rollover::ToOne);
+
+        let count = Ack::new(0).serialize(&mut rx);
+        machine
+            .process(Instant::now(), peer(), &rx[0..count], &mut tx)
+            .unwrap();
+        let mut sent = 1;
+        while machine.poll_transmit(&mut tx).unwrap().is_some() {
+            sent += 1;
+        }
+        assert_eq!(sent, u16::MAX);
+
+        // The block after 65535 is numbered 1 again.
+        let count = Ack::new(u16::MAX).serialize(&mut rx);
+        assert_eq!(
+            machine.process(Instant::now(), peer(), &rx[0..count], &mut tx),
+            Ok(Event::Transmit(12))
+        );
+        assert_eq!(&tx[0..5], &[0x0, 0x3, 0x0, 0x1, 0xA5]);
+        assert_eq!(machine.poll_transmit(&mut tx), Ok(Some(7)));
+        assert_eq!(&tx[0..4], &[0x0, 0x3, 0x0, 0x2]);
+
+        let count = Ack::new(2).serialize(&mut rx);
+        assert_eq!(
+            machine.process(Instant::now(), peer(), &rx[0..count], &mut tx),
+            Ok(Event::TransferComplete {
+                bytes: 524291,
+                blocks: 65537,
+                transmit: None
+            })
+        );
+        assert!(!machine.is_busy());
+    }
+
+    #[test]
+    fn test_receive_past_block_number_rollover() {
+        let mut my_file: Vec<u8> = Vec::new();
+        let mut tx = [0u8; MAX_PACKET_SIZE];
+        let mut rx = [0u8; MAX_PACKET_SIZE];
+        let incoming_data: Vec<u8> = vec![0x5A; 8 * 65536 + 3];
+
+        {
+            let mut machine = Machine::new();
+            machine
+                .set_options(vec![TransferOption::block_size(8)])
+                .unwrap();
+            machine
+                .request_receive_file(Instant::now(), String::from("ABCDE"), &mut my_file, &mut tx)
+                .unwrap();
+            let count = OptionAck::new(vec![TransferOption::block_size(8)]).serialize(&mut rx);
+            machine
+                .process(Instant::now(), peer(), &rx[0..count], &mut tx)
+                .unwrap();
+            for block in 1..=65537u64 {
+                let payload = file_block(&incoming_data, block, 8).unwrap();
+                let count = Data::new(block as u16, payload).serialize(&mut rx);
+                let event = machine
+                    .process(Instant::now(), peer(), &rx[0..count], &mut tx)
+                    .unwrap();
+                assert_eq!(event.transmit(), Some(4));
+                assert_eq!(tx[2..4], (block as u16).to_be_bytes());
+            }
+            assert!(!machine.is_busy());
+        }
+        assert_eq!(my_file, incoming_data);
+    }
+
+    #[test]
+    fn test_retransmit_after_timeout() {
+        let mut my_file: Vec<u8> = Vec::new();
+        let mut tx = [0u8; MAX_PACKET_SIZE];
+        let mut rx = [0u8; MAX_PACKET_SIZE];
+        let incoming_data: Vec<u8> = vec![0x5A; 12];
+        let start = Instant::now();
+
+        let mut machine = Machine::new();
+        machine
+            .set_retransmit_timeout(Duration::from_millis(100))
+            .unwrap();
+        machine
+            .set_options(vec![
+                TransferOption::block_size(8),
+                TransferOption::window_size(2),
+            ])
+            .unwrap();
+        let count = machine
+            .request_receive_file(start, String::from("ABCDE"), &mut my_file, &mut tx)
+            .unwrap();
+        let request = tx[0..count].to_vec();
+        assert_eq!(
+            machine.poll_timeout(),
+            Some(start + Duration::from_millis(100))
+        );
+
+        // Nothing is due before the deadline.
+        assert_eq!(
+            machine.handle_timeout(start + Duration::from_millis(99), &mut tx),
+            Ok(Event::NeedTimer(start + Duration::from_millis(100)))
+        );
+        // The request goes out again once it passes.
+        let now = start + Duration::from_millis(100);
+        assert_eq!(
+            machine.handle_timeout(now, &mut tx),
+            Ok(Event::Transmit(count))
+        );
+        assert_eq!(&tx[0..count], &request[..]);
+        assert_eq!(machine.retries(), 1);
+        assert_eq!(
+            machine.poll_timeout(),
+            Some(now + Duration::from_millis(100))
+        );
+
+        // Progress restarts the timer.
+        let now = start + Duration::from_millis(150);
+        let count = OptionAck::new(vec![
+            TransferOption::block_size(8),
+            TransferOption::window_size(2),
+        ])
+        .serialize(&mut rx);
+        assert_eq!(
+            machine.process(now, peer(), &rx[0..count], &mut tx),
+            Ok(Event::Transmit(4))
+        );
+        assert_eq!(machine.retries(), 0);
+        assert_eq!(
+            machine.poll_timeout(),
+            Some(now + Duration::from_millis(100))
+        );
+
+        // Halfway through a window, the blocks received so far are acknowledged.
+        let count = Data::new(1, file_block(&incoming_data, 1, 8).unwrap()).serialize(&mut rx);
+        assert_eq!(
+            machine.process(now, peer(), &rx[0..count], &mut tx),
+            Ok(Event::NeedTimer(now + Duration::from_millis(100)))
+        );
+        let now = now + Duration::from_millis(100);
+        assert_eq!(machine.handle_timeout(now, &mut tx), Ok(Event::Transmit(4)));
+        assert_eq!(&tx[0..4], &[0x0, 0x4, 0x0, 0x1]);
+
+        let count = Data::new(2, file_block(&incoming_data, 2, 8).unwrap()).serialize(&mut rx);
+        assert_eq!(
+            machine.process(now, peer(), &rx[0..count], &mut tx),
+            Ok(Event::TransferComplete {
+                bytes: 12,
+                blocks: 2,
+                transmit: Some(4)
+            })
+        );
+        assert!(!machine.is_busy());
+        assert_eq!(machine.poll_timeout(), None);
+        drop(machine);
+        assert_eq!(my_file, incoming_data);
+    }
+
+    #[test]
+    fn test_retransmit_window_after_timeout() {
+        let my_file: Vec<u8> = [0x5A; 20].to_vec();
+        let mut tx = [0u8; MAX_PACKET_SIZE];
+        let mut rx = [0u8; MAX_PACKET_SIZE];
+        let start = Instant::now();
+
+        let mut machine = Machine::new();
+        let request = Request::new(TransferType::Read, Mode::Binary, String::from("ABCDE"))
+            .unwrap()
+            .with_options(vec![
+                TransferOption::block_size(8),
+                TransferOption::window_size(4),
+                TransferOption::timeout(2),
+            ])
+            .unwrap();
+        let count = request.serialize(&mut rx);
+        machine.listen_for_request(peer(), &rx[0..count]).unwrap();
+        machine
+            .accept_option(TransferOption::block_size(8))
+            .unwrap();
+        machine
+            .accept_option(TransferOption::window_size(4))
+            .unwrap();
+        machine.accept_option(TransferOption::timeout(2)).unwrap();
+        machine.reply_send_file(start, &my_file, &mut tx).unwrap();
+        let count = Ack::new(0).serialize(&mut rx);
+        machine
+            .process(start, peer(), &rx[0..count], &mut tx)
+            .unwrap();
+        while machine.poll_transmit(&mut tx).unwrap().is_some() {}
+
+        // The negotiated timeout applies, and the whole window goes out again.
+        assert_eq!(machine.poll_timeout(), Some(start + Duration::from_secs(2)));
+        let now = start + Duration::from_secs(2);
+        assert_eq!(
+            machine.handle_timeout(now, &mut tx),
+            Ok(Event::Transmit(12))
+        );
+        assert_eq!(tx[3], 1);
+        assert_eq!(machine.poll_transmit(&mut tx), Ok(Some(12)));
+        assert_eq!(tx[3], 2);
+        assert_eq!(machine.poll_transmit(&mut tx), Ok(Some(8)));
+        assert_eq!(tx[3], 3);
+        assert_eq!(machine.poll_transmit(&mut tx), Ok(None));
+    }
+
+    #[test]
+    fn test_fail_after_max_retries() {
+        let my_file: Vec<u8> = [0x5A; 8].to_vec();
+        let mut tx = [0u8; MAX_PACKET_SIZE];
+        let mut now = Instant::now();
+
+        let mut machine = Machine::new();
+        machine.set_max_retries(2).unwrap();
+        let count = machine
+            .request_send_file(now, String::from("ABCDE"), &my_file, &mut tx)
+            .unwrap();
+        assert_eq!(machine.set_max_retries(3), Err(TftprsError::Busy));
+        for _ in 0..2 {
+            now = machine.poll_timeout().unwrap();
+            assert_eq!(
+                machine.handle_timeout(now, &mut tx),
+                Ok(Event::Transmit(count))
+            );
+        }
+        now = machine.poll_timeout().unwrap();
+        assert_eq!(
+            machine.handle_timeout(now, &mut tx),
+            Err(TftprsError::Timeout)
+        );
+        assert!(!machine.is_busy());
+        assert_eq!(machine.poll_timeout(), None);
+    }
+
+    #[test]
+    fn test_acknowledge_duplicate_data() {
+        let mut my_file: Vec<u8> = Vec::new();
+        let mut tx = [0u8; MAX_PACKET_SIZE];
+        let mut rx = [0u8; MAX_PACKET_SIZE];
+        let incoming_data: Vec<u8> = vec![0xA5; DEFAULT_BLOCK_SIZE + 4];
+
+        {
+            let mut machine = Machine::new();
+            machine
+                .request_receive_file(Instant::now(), String::from("ABCDE"), &mut my_file, &mut tx)
+                .unwrap();
+            let count = Data::new(
+                1,
+                file_block(&incoming_data, 1, DEFAULT_BLOCK_SIZE).unwrap(),
+            )
+            .serialize(&mut rx);
+            assert_eq!(
+                machine.process(Instant::now(), peer(), &rx[0..count], &mut tx),
+                Ok(Event::Transmit(4))
+            );
+
+            // The sender missed the ack and sends the block again.
+            assert_eq!(
+                machine.process(Instant::now(), peer(), &rx[0..count], &mut tx),
+                Ok(Event::Transmit(4))
+            );
+            assert_eq!(&tx[0..4], &[0x0, 0x4, 0x0, 0x1]);
+            assert!(machine.is_busy());
+
+            let count = Data::new(
+                2,
+                file_block(&incoming_data, 2, DEFAULT_BLOCK_SIZE).unwrap(),
+            )
+            .serialize(&mut rx);
+            assert_eq!(
+                machine.process(Instant::now(), peer(), &rx[0..count], &mut tx),
+                Ok(Event::TransferComplete {
+                    bytes: 516,
+                    blocks: 2,
+                    transmit: Some(4)
+                })
+            );
+            assert!(!machine.is_busy());
+        }
+        // The duplicate was not written twice.
+        assert_eq!(my_file, incoming_data);
+    }
+
+    #[test]
+    fn test_ignore_duplicate_ack() {
+        let my_file: Vec<u8> = [0x5A; DEFAULT_BLOCK_SIZE + 4].to_vec();
+        let mut tx = [0u8; MAX_PACKET_SIZE];
+        let mut rx = [0u8; MAX_PACKET_SIZE];
+
+        let mut machine = Machine::new();
+        machine
+            .request_send_file(Instant::now(), String::from("ABCDE"), &my_file, &mut tx)
+            .unwrap();
+        let count = Ack::new(0).serialize(&mut rx);
+        let event = machine
+            .process(Instant::now(), peer(), &rx[0..count], &mut tx)
+            .unwrap();
+        assert_eq!(
+            event,
+            Event::Transmit(DEFAULT_BLOCK_SIZE + FIXED_DATA_BYTES)
+        );
+
+        // A delayed duplicate of the ack does not trigger another copy of block 1.
+        assert_eq!(
+            machine.process(Instant::now(), peer(), &rx[0..count], &mut tx),
+            Ok(Event::Ignored(IgnoreReason::DuplicateAck))
+        );
+        assert_eq!(machine.poll_transmit(&mut tx), Ok(None));
+
+        let count = Ack::new(1).serialize(&mut rx);
+        assert_eq!(
+            machine.process(Instant::now(), peer(), &rx[0..count], &mut tx),
+            Ok(Event::Transmit(8))
+        );
+        assert_eq!(tx[3], 2);
+        assert_eq!(
+            machine.process(Instant::now(), peer(), &rx[0..count], &mut tx),
+            Ok(Event::Ignored(IgnoreReason::DuplicateAck))
+        );
+
+        let count = Ack::new(2).serialize(&mut rx);
+        assert_eq!(
+            machine.process(Instant::now(), peer(), &rx[0..count], &mut tx),
+            Ok(Event::TransferComplete {
+                bytes: 516,
+                blocks: 2,
+                transmit: None
+            })
+        );
+        assert!(!machine.is_busy());
+    }
+
+    #[test]
+    fn test_reject_unknown_transfer_id() {
+        let mut my_file: Vec<u8> = Vec::new();
+        let mut tx = [0u8; MAX_PACKET_SIZE];
+        let mut rx = [0u8; MAX_PACKET_SIZE];
+        let incoming_data: Vec<u8> = vec![0xA5; 4];
+        let stranger = SocketAddr::from(([127, 0, 0, 1], 49153));
+
+        {
+            let mut machine = Machine::new();
+            machine
+                .request_receive_file(Instant::now(), String::from("ABCDE"), &mut my_file, &mut tx)
+                .unwrap();
+            assert_eq!(machine.peer(), None);
+
+            // The first reply picks the peer.
+            let count = Data::new(1, &[0xFF; DEFAULT_BLOCK_SIZE]).serialize(&mut rx);
+            assert_eq!(
+                machine.process(Instant::now(), peer(), &rx[0..count], &mut tx),
+                Ok(Event::Transmit(4))
+            );
+            assert_eq!(machine.peer(), Some(peer()));
+
+            // Anyone else is turned away, and the transfer carries on.
+            let count = Data::new(2, &[0x00; 4]).serialize(&mut rx);
+            let expected = ErrorResponse::new(
+                ErrorCode::UnknownTransferId,
+                String::from("Unknown transfer ID"),
+            );
+            let mut expected_buffer = [0u8; MAX_PACKET_SIZE];
+            let expected_count = expected.serialize(&mut expected_buffer);
+            assert_eq!(
+                machine.process(Instant::now(), stranger, &rx[0..count], &mut tx),
+                Err(TftprsError::UnknownTransferId(expected_count))
+            );
+            assert_eq!(tx[0..expected_count], expected_buffer[0..expected_count]);
+            assert!(machine.is_busy());
+
+            // Errors from anyone else are not answered.
+            let error = ErrorResponse::new(ErrorCode::Undefined, String::from("Go away"));
+            let count = error.serialize(&mut rx);
+            assert_eq!(
+                machine.process(Instant::now(), stranger, &rx[0..count], &mut tx),
+                Ok(Event::Ignored(IgnoreReason::ForeignError))
+            );
+            assert!(machine.is_busy());
+
+            let count = Data::new(2, &incoming_data).serialize(&mut rx);
+            assert_eq!(
+                machine.process(Instant::now(), peer(), &rx[0..count], &mut tx),
+                Ok(Event::TransferComplete {
+                    bytes: 516,
+                    blocks: 2,
+                    transmit: Some(4)
+                })
+            );
+            assert!(!machine.is_busy());
+        }
+        assert_eq!(my_file.len(), DEFAULT_BLOCK_SIZE + 4);
+        assert_eq!(my_file[DEFAULT_BLOCK_SIZE..], incoming_data[..]);
+    }
+
+    #[test]
+    fn test_send_netascii() {
+        let my_file: Vec<u8> = b"abcdefg\nhijklm\rno".to_vec();
+        let mut tx = [0u8; MAX_PACKET_SIZE];
+        let mut rx = [0u8; MAX_PACKET_SIZE];
+
+        let mut machine = Machine::new();
+        let request = Request::new(TransferType::Read, Mode::Text, String::from("ABCDE"))
+            .unwrap()
+            .with_options(vec![
+                TransferOption::block_size(8),
+                TransferOption::window_size(2),
+            ])
+            .unwrap();
+        let count = request.serialize(&mut rx);
+        machine.listen_for_request(peer(), &rx[0..count]).unwrap();
+        machine
+            .accept_option(TransferOption::block_size(8))
+            .unwrap();
+        machine
+            .accept_option(TransferOption::window_size(2))
+            .unwrap();
+        machine
+            .reply_send_file(Instant::now(), &my_file, &mut tx)
+            .unwrap();
+
+        // Line endings grow the file, and may be split between blocks.
+        let count = Ack::new(0).serialize(&mut rx);
+        assert_eq!(
+            machine.process(Instant::now(), peer(), &rx[0..count], &mut tx),
+            Ok(Event::Transmit(12))
+        );
+        assert_eq!(&tx[0..12], b"\x00\x03\x00\x01abcdefg\r");
+        assert_eq!(machine.poll_transmit(&mut tx), Ok(Some(12)));
+        assert_eq!(&tx[0..12], b"\x00\x03\x00\x02\nhijklm\r");
+
+        // Going back to the second block encodes it the same way again.
+        let count = Ack::new(1).serialize(&mut rx);
+        assert_eq!(
+            machine.process(Instant::now(), peer(), &rx[0..count], &mut tx),
+            Ok(Event::Transmit(12))
+        );
+        assert_eq!(&tx[0..12], b"\x00\x03\x00\x02\nhijklm\r");
+        assert_eq!(machine.poll_transmit(&mut tx), Ok(Some(7)));
+        assert_eq!(&tx[0..7], b"\x00\x03\x00\x03\x00no");
+        assert_eq!(machine.poll_transmit(&mut tx), Ok(None));
+
+        let count = Ack::new(3).serialize(&mut rx);
+        assert_eq!(
+            machine.process(Instant::now(), peer(), &rx[0..count], &mut tx),
+            Ok(Event::TransferComplete {
+                bytes: 17,
+                blocks: 3,
+                transmit: None
+            })
+        );
+        assert!(!machine.is_busy());
+    }
+
+    #[test]
+    fn test_receive_netascii() {
+        let mut my_file: Vec<u8> = Vec::new();
+        let mut tx = [0u8; MAX_PACKET_SIZE];
+        let mut rx = [0u8; MAX_PACKET_SIZE];
+
+        {
+            let mut machine = Machine::new();
+            machine.set_mode(Mode::Text).unwrap();
+            machine
+                .set_options(vec![TransferOption::block_size(8)])
+                .unwrap();
+            machine
+                .request_receive_file(Instant::now(), String::from("ABCDE"), &mut my_file, &mut tx)
+                .unwrap();
+            let count = OptionAck::new(vec![TransferOption::block_size(8)]).serialize(&mut rx);
+            machine
+                .process(Instant::now(), peer(), &rx[0..count], &mut tx)
+                .unwrap();
+            let blocks: [&[u8]; 3] = [b"abcdefg\r", b"\nhijklm\r", b"\0no\r\n"];
+            for (block, payload) in blocks.iter().enumerate() {
+                let count = Data::new(block as u16 + 1, payload).serialize(&mut rx);
+                let event = machine
+                    .process(Instant::now(), peer(), &rx[0..count], &mut tx)
+                    .unwrap();
+                assert_eq!(event.transmit(), Some(4));
+            }
+            assert!(!machine.is_busy());
+        }
+        assert_eq!(my_file, b"abcdefg\nhijklm\rno\n");
+    }
+
+    #[test]
+    fn test_parse_mode_ignoring_case() {
+        let mut rx = [0u8; MAX_PACKET_SIZE];
+        let mut machine = Machine::new();
+
+        let request = b"\x00\x01ABCDE\x00octet\x00";
+        rx[0..request.len()].copy_from_slice(request);
+        machine
+            .listen_for_request(peer(), &rx[0..request.len()])
+            .unwrap();
+        assert_eq!(machine.mode(), Mode::Binary);
+        machine.reset();
+
+        let request = b"\x00\x02ABCDE\x00NetAscii\x00";
+        rx[0..request.len()].copy_from_slice(request);
+        machine
+            .listen_for_request(peer(), &rx[0..request.len()])
+            .unwrap();
+        assert_eq!(machine.mode(), Mode::Text);
+        machine.reset();
+
+        // Aliases are only for the lenient profile.
+        let request = b"\x00\x01ABCDE\x00binary\x00";
+        rx[0..request.len()].copy_from_slice(request);
+        assert_eq!(
+            machine.listen_for_request(peer(), &rx[0..request.len()]),
+            Err(TftprsError::BadPacketReceived)
+        );
+        machine.reset();
+        machine.set_strictness(Strictness::Lenient).unwrap();
+        machine
+            .listen_for_request(peer(), &rx[0..request.len()])
+            .unwrap();
+        assert_eq!(machine.mode(), Mode::Binary);
+
+        // So are the zeros some clients pad their packets with.
+        machine.reset();
+        machine.listen_for_request(peer(), &rx).unwrap();
+        assert_eq!(machine.mode(), Mode::Binary);
+        machine.reset();
+        machine.set_strictness(Strictness::Strict).unwrap();
+        let request = b"\x00\x01ABCDE\x00octet\x00";
+        rx[0..request.len()].copy_from_slice(request);
+        assert_eq!(
+            machine.listen_for_request(peer(), &rx),
+            Err(TftprsError::BadPacketReceived)
+        );
+    }
+
+    #[test]
+    fn test_receive_mail() {
+        let mut tx = [0u8; MAX_PACKET_SIZE];
+        let mut rx = [0u8; MAX_PACKET_SIZE];
+        let mut delivered: Vec<(String, Vec<u8>)> = Vec::new();
+        let mut sink = |recipient: &str, message: &[u8]| {
+            delivered.push((recipient.to_string(), message.to_vec()));
+        };
+
+        {
+            let mut machine = Machine::new();
+            let request = b"\x00\x02postmaster\x00Mail\x00";
+            rx[0..request.len()].copy_from_slice(request);
+            let filename = machine
+                .listen_for_request(peer(), &rx[0..request.len()])
+                .unwrap();
+            assert_eq!(filename, "postmaster");
+            assert_eq!(machine.mode(), Mode::Mail);
+            assert_eq!(machine.recipient(), Some("postmaster"));
+            assert_eq!(
+                machine.reply_receive_mail(Instant::now(), &mut sink, &mut tx),
+                Ok(4)
+            );
+
+            let count = Data::new(1, b"Hello\r\nworld\r\n").serialize(&mut rx);
+            assert_eq!(
+                machine.process(Instant::now(), peer(), &rx[0..count], &mut tx),
+                Ok(Event::TransferComplete {
+                    bytes: 12,
+                    blocks: 1,
+                    transmit: Some(4)
+                })
+            );
+            assert!(!machine.is_busy());
+        }
+        assert_eq!(
+            delivered,
+            vec![(String::from("postmaster"), b"Hello\nworld\n".to_vec())]
+        );
+    }
+
+    #[test]
+    fn test_reject_mail() {
+        let my_file: Vec<u8> = Vec::new();
+        let mut tx = [0u8; MAX_PACKET_SIZE];
+        let mut rx = [0u8; MAX_PACKET_SIZE];
+
+        let mut machine = Machine::new();
+        let request = b"\x00\x02postmaster\x00MAIL\x00";
+        rx[0..request.len()].copy_from_slice(request);
+        machine
+            .listen_for_request(peer(), &rx[0..request.len()])
+            .unwrap();
+        let count = machine.reject_mail(&mut tx).unwrap();
+        assert_eq!(
+            &tx[0..4],
+            &[0x0, 0x5, 0x0, ErrorCode::IllegalOperation as u8]
+        );
+        assert_eq!(&tx[4..count], b"Mail is not accepted\0");
+        assert!(!machine.is_busy());
+
+        // Mail can never be read.
+        let request = b"\x00\x01postmaster\x00mail\x00";
+        rx[0..request.len()].copy_from_slice(request);
+        machine
+            .listen_for_request(peer(), &rx[0..request.len()])
+            .unwrap();
+        assert_eq!(machine.recipient(), None);
+        assert_eq!(
+            machine.reply_send_file(Instant::now(), &my_file, &mut tx),
+            Err(TftprsError::BadRequestAttempted)
+        );
+    }
+
+    #[test]
+    fn test_slice_buffers() {
+        let my_file: Vec<u8> = [0x5A; 1024].to_vec();
+        let mut small_tx = [0u8; 64];
+        let mut tx = [0u8; 600];
+        let mut rx = [0u8; 600];
+
+        let mut machine = Machine::new();
+        let count = Request::new(TransferType::Read, Mode::Binary, String::from("ABCDE"))
+            .unwrap()
+            .serialize(&mut rx);
+        machine.listen_for_request(peer(), &rx[0..count]).unwrap();
+
+        // A full block does not fit.
+        assert_eq!(
+            machine.reply_send_file(Instant::now(), &my_file, &mut small_tx),
+            Err(TftprsError::BufferTooSmall {
+                needed: DEFAULT_BLOCK_SIZE + FIXED_DATA_BYTES,
+                available: 64
+            })
+        );
+        assert_eq!(
+            machine.handle_timeout(machine.poll_timeout().unwrap(), &mut tx),
+            Ok(Event::Transmit(DEFAULT_BLOCK_SIZE + FIXED_DATA_BYTES))
+        );
+        assert_eq!(tx[3], 1);
+
+        // A datagram is only as long as its slice.
+        let count = Ack::new(1).serialize(&mut rx);
+        rx[count] = 0xFF;
+        assert_eq!(
+            machine.process(Instant::now(), peer(), &rx[0..count + 1], &mut tx),
+            Err(TftprsError::BadPacketReceived)
+        );
+        assert_eq!(
+            machine.process(Instant::now(), peer(), &rx[0..count], &mut tx),
+            Ok(Event::Transmit(DEFAULT_BLOCK_SIZE + FIXED_DATA_BYTES))
+        );
+        assert_eq!(tx[3], 2);
+    }
+
+    #[test]
+    fn test_send_from_reader() {
+        let my_file: Vec<u8> = (0u8..20).collect();
+        let mut tx = [0u8; MAX_PACKET_SIZE];
+        let mut rx = [0u8; MAX_PACKET_SIZE];
+
+        let mut machine = Machine::new();
+        let count = Request::new(TransferType::Read, Mode::Binary, String::from("ABCDE"))
+            .unwrap()
+            .with_options(vec![
+                TransferOption::block_size(8),
+                TransferOption::transfer_size(0),
+            ])
+            .unwrap()
+            .serialize(&mut rx);
+        machine.listen_for_request(peer(), &rx[0..count]).unwrap();
+        machine
+            .accept_option(TransferOption::block_size(8))
+            .unwrap();
+        machine
+            .accept_option(TransferOption::transfer_size(0))
+            .unwrap();
+
+        // The length of a reader is not known, so the transfer size is left out.
+        let count = machine
+            .reply_send_file(Instant::now(), ReadSource::new(&my_file[..]), &mut tx)
+            .unwrap();
+        assert_eq!(&tx[0..count], b"\x00\x06blksize\x008\x00");
+
+        // The file ends with the first short read.
+        let mut received = Vec::new();
+        for block in 0..3 {
+            let count = Ack::new(block).serialize(&mut rx);
+            let Ok(Event::Transmit(count)) =
+                machine.process(Instant::now(), peer(), &rx[0..count], &mut tx)
+            else {
+                panic!("Block {} was not sent", block + 1);
+            };
+            assert_eq!(tx[3], block as u8 + 1);
+            received.extend_from_slice(&tx[FIXED_DATA_BYTES..count]);
+        }
+        assert_eq!(received, my_file);
+        let count = Ack::new(3).serialize(&mut rx);
+        assert_eq!(
+            machine.process(Instant::now(), peer(), &rx[0..count], &mut tx),
+            Ok(Event::TransferComplete {
+                bytes: 20,
+                blocks: 3,
+                transmit: None
+            })
+        );
+        assert!(!machine.is_busy());
+    }
+
+    #[test]
+    fn test_receive_into_writer() {
+        let mut my_file = WriteSink::new(Vec::new());
+        let mut tx = [0u8; MAX_PACKET_SIZE];
+        let mut rx = [0u8; MAX_PACKET_SIZE];
+
+        {
+            let mut machine = Machine::new();
+            let count = Request::new(TransferType::Write, Mode::Binary, String::from("ABCDE"))
+                .unwrap()
+                .serialize(&mut rx);
+            machine.listen_for_request(peer(), &rx[0..count]).unwrap();
+            machine
+                .reply_receive_file(Instant::now(), &mut my_file, &mut tx)
+                .unwrap();
+            let count = Data::new(1, b"Hello").serialize(&mut rx);
+            assert_eq!(
+                machine.process(Instant::now(), peer(), &rx[0..count], &mut tx),
+                Ok(Event::TransferComplete {
+                    bytes: 5,
+                    blocks: 1,
+                    transmit: Some(4)
+                })
+            );
+            assert!(!machine.is_busy());
+        }
+        assert_eq!(my_file.into_inner(), b"Hello");
+    }
+
+    #[test]
+    fn test_serve_shared_image() {
+        let image: Arc<[u8]> = Arc::from(vec![0x5A; 600]);
+        let mut rx = [0u8; MAX_PACKET_SIZE];
+
+        // Each client gets its own machine, and all of them read the same image.
+        let mut machines: HashMap<SocketAddr, Machine<'static>> = HashMap::new();
+        for port in 49152..49155 {
+            let client = SocketAddr::from(([127, 0, 0, 1], port));
+            let mut tx = [0u8; MAX_PACKET_SIZE];
+            let mut machine = Machine::new();
+            let count = Request::new(TransferType::Read, Mode::Binary, String::from("image"))
+                .unwrap()
+                .serialize(&mut rx);
+            machine.listen_for_request(client, &rx[0..count]).unwrap();
+            assert_eq!(
+                machine.reply_send_file(Instant::now(), image.clone(), &mut tx),
+                Ok(DEFAULT_BLOCK_SIZE + FIXED_DATA_BYTES)
+            );
+            machines.insert(client, machine);
+        }
+        assert_eq!(Arc::strong_count(&image), 4);
+
+        // A machine can finish its transfer on another thread.
+        let mut machine = machines.remove(&peer()).unwrap();
+        let handle = std::thread::spawn(move || {
+            let mut tx = [0u8; MAX_PACKET_SIZE];
+            let mut rx = [0u8; MAX_PACKET_SIZE];
+            let count = Ack::new(1).serialize(&mut rx);
+            let event = machine
+                .process(Instant::now(), peer(), &rx[0..count], &mut tx)
+                .unwrap();
+            assert_eq!(
+                event,
+                Event::Transmit(600 - DEFAULT_BLOCK_SIZE + FIXED_DATA_BYTES)
+            );
+            let count = Ack::new(2).serialize(&mut rx);
+            machine
+                .process(Instant::now(), peer(), &rx[0..count], &mut tx)
+                .unwrap();
+            assert!(!machine.is_busy());
+        });
+        handle.join().unwrap();
+        assert_eq!(Arc::strong_count(&image), 3);
+    }
+
+    #[test]
+    fn test_transfer_state() {
+        let my_file: Vec<u8> = [0x5A; 600].to_vec();
+        let mut tx = [0u8; MAX_PACKET_SIZE];
+        let mut rx = [0u8; MAX_PACKET_SIZE];
+
+        // Sending a file to the peer.
+        let mut machine = Machine::new();
+        assert_eq!(machine.state(), TransferState::Idle);
+        let count = Request::new(TransferType::Read, Mode::Binary, String::from("ABCDE"))
+            .unwrap()
+            .with_options(vec![TransferOption::transfer_size(0)])
+            .unwrap()
+            .serialize(&mut rx);
+        machine.listen_for_request(peer(), &rx[0..count]).unwrap();
+        assert_eq!(machine.state(), TransferState::RequestReceived);
+        machine
+            .accept_option(TransferOption::transfer_size(0))
+            .unwrap();
+        machine
+            .reply_send_file(Instant::now(), &my_file, &mut tx)
+            .unwrap();
+        assert_eq!(machine.state(), TransferState::AwaitingAck(0));
+        for block in 0..2 {
+            let count = Ack::new(block).serialize(&mut rx);
+            machine
+                .process(Instant::now(), peer(), &rx[0..count], &mut tx)
+                .unwrap();
+            assert_eq!(
+                machine.state(),
+                TransferState::AwaitingAck(block as u64 + 1)
+            );
+        }
+        assert_eq!(machine.bytes_transferred(), 512);
+        assert_eq!(machine.blocks_transferred(), 1);
+        let count = Ack::new(2).serialize(&mut rx);
+        machine
+            .process(Instant::now(), peer(), &rx[0..count], &mut tx)
+            .unwrap();
+        assert_eq!(machine.state(), TransferState::Completed);
+        assert_eq!(machine.bytes_transferred(), 600);
+        assert_eq!(machine.blocks_transferred(), 2);
+
+        // In the middle of a window, the state names the end of the window, whether or not it was all transmitted.
+        let my_file: Vec<u8> = [0x5A; 2600].to_vec();
+        let mut machine = Machine::new();
+        let count = Request::new(TransferType::Read, Mode::Binary, String::from("ABCDE"))
+            .unwrap()
+            .with_options(vec![TransferOption::window_size(4)])
+            .unwrap()
+            .serialize(&mut rx);
+        machine.listen_for_request(peer(), &rx[0..count]).unwrap();
+        machine
+            .accept_option(TransferOption::window_size(4))
+            .unwrap();
+        machine
+            .reply_send_file(Instant::now(), &my_file, &mut tx)
+            .unwrap();
+        let count = Ack::new(0).serialize(&mut rx);
+        machine
+            .process(Instant::now(), peer(), &rx[0..count], &mut tx)
+            .unwrap();
+        assert_eq!(machine.state(), TransferState::AwaitingAck(4));
+        machine.poll_transmit(&mut tx).unwrap();
+        assert_eq!(machine.state(), TransferState::AwaitingAck(4));
+        while machine.poll_transmit(&mut tx).unwrap().is_some() {}
+        assert_eq!(machine.state(), TransferState::AwaitingAck(4));
+
+        // The final window ends at the final block.
+        let count = Ack::new(4).serialize(&mut rx);
+        machine
+            .process(Instant::now(), peer(), &rx[0..count], &mut tx)
+            .unwrap();
+        assert_eq!(machine.state(), TransferState::AwaitingAck(6));
+
+        // Receiving a file from the peer.
+        let mut my_file: Vec<u8> = Vec::new();
+        let mut machine = Machine::new();
+        machine
+            .request_receive_file(Instant::now(), String::from("ABCDE"), &mut my_file, &mut tx)
+            .unwrap();
+        assert_eq!(machine.state(), TransferState::RequestSent);
+        assert_eq!(machine.bytes_transferred(), 0);
+        let count = Data::new(1, &[0x5A; DEFAULT_BLOCK_SIZE]).serialize(&mut rx);
+        machine
+            .process(Instant::now(), peer(), &rx[0..count], &mut tx)
+            .unwrap();
+        assert_eq!(machine.state(), TransferState::AwaitingData(2));
+        assert_eq!(machine.bytes_transferred(), DEFAULT_BLOCK_SIZE as u64);
+        assert_eq!(machine.blocks_transferred(), 1);
+        let count =
+            ErrorResponse::new(ErrorCode::DiskFull, String::from("Disk full")).serialize(&mut rx);
+        machine
+            .process(Instant::now(), peer(), &rx[0..count], &mut tx)
+            .unwrap();
+        assert_eq!(machine.state(), TransferState::Failed);
+        machine.reset();
+        assert_eq!(machine.state(), TransferState::Idle);
+    }
+
+    #[test]
+    fn test_reply_to_violations() {
+        let my_file: Vec<u8> = [0x5A; 1024].to_vec();
+        let mut tx = [0u8; MAX_PACKET_SIZE];
+        let mut rx = [0u8; MAX_PACKET_SIZE];
+        let request =
+            Request::new(TransferType::Read, Mode::Binary, String::from("ABCDE")).unwrap();
+
+        // By default, the host only hears about a violation, and the transfer carries on.
+        let mut machine = Machine::new();
+        machine
+            .request_send_file(Instant::now(), String::from("ABCDE"), &my_file, &mut tx)
+            .unwrap();
+        let count = Ack::new(0).serialize(&mut rx);
+        machine
+            .process(Instant::now(), peer(), &rx[0..count], &mut tx)
+            .unwrap();
+        let count = request.serialize(&mut rx);
+        assert_eq!(
+            machine.process(Instant::now(), peer(), &rx[0..count], &mut tx),
+            Err(TftprsError::Busy)
+        );
+        assert!(machine.is_busy());
+
+        // Otherwise, the peer is told, and the transfer ends.
+        let mut machine = Machine::new();
+        machine.set_reply_to_violations(true).unwrap();
+        machine
+            .request_send_file(Instant::now(), String::from("ABCDE"), &my_file, &mut tx)
+            .unwrap();
+        let count = Ack::new(0).serialize(&mut rx);
+        machine
+            .process(Instant::now(), peer(), &rx[0..count], &mut tx)
+            .unwrap();
+        let count = Data::new(1, b"Hello").serialize(&mut rx);
+        assert_eq!(
+            machine.process(Instant::now(), peer(), &rx[0..count], &mut tx),
+            Err(TftprsError::IllegalOperation(20))
+        );
+        assert_eq!(&tx[0..20], b"\x00\x05\x00\x04Unexpected data\x00");
+        assert_eq!(machine.state(), TransferState::Failed);
+
+        // A malformed packet is only answered once the peer is known.
+        machine
+            .request_send_file(Instant::now(), String::from("ABCDE"), &my_file, &mut tx)
+            .unwrap();
+        assert_eq!(
+            machine.process(Instant::now(), peer(), &[0x0, 0x9, 0x0], &mut tx),
+            Err(TftprsError::BadPacketReceived)
+        );
+        assert!(machine.is_busy());
+        let count = Ack::new(0).serialize(&mut rx);
+        machine
+            .process(Instant::now(), peer(), &rx[0..count], &mut tx)
+            .unwrap();
+        assert_eq!(
+            machine.process(Instant::now(), peer(), &[0x0, 0x9, 0x0], &mut tx),
+            Err(TftprsError::IllegalOperation(21))
+        );
+        assert_eq!(tx[3], ErrorCode::IllegalOperation as u8);
+        assert!(!machine.is_busy());
+    }
+
+    #[test]
+    fn test_cancel() {
+        let mut my_file: Vec<u8> = b"Old".to_vec();
+        let mut tx = [0u8; MAX_PACKET_SIZE];
+        let mut rx = [0u8; MAX_PACKET_SIZE];
+        let mut machine = Machine::new();
+        assert_eq!(
+            machine.cancel(String::from("Stop"), &mut tx),
+            Err(TftprsError::NoConnection)
+        );
+
+        // A cancelled upload leaves the file as it was before.
+        machine
+            .request_receive_file(Instant::now(), String::from("ABCDE"), &mut my_file, &mut tx)
+            .unwrap();
+        let count = Data::new(1, &[0x5A; DEFAULT_BLOCK_SIZE]).serialize(&mut rx);
+        machine
+            .process(Instant::now(), peer(), &rx[0..count], &mut tx)
+            .unwrap();
+        let summary = machine.cancel(String::from("Stop"), &mut tx).unwrap();
+        assert_eq!(
+            summary,
+            TransferSummary {
+                transfer_type: TransferType::Read,
+                state: TransferState::AwaitingData(2),
+                bytes: DEFAULT_BLOCK_SIZE as u64,
+                blocks: 1,
+                transmit: 9,
+            }
+        );
+        assert_eq!(&tx[0..9], b"\0\x05\0\0Stop\0");
+        assert_eq!(machine.state(), TransferState::Failed);
+        drop(machine);
+        assert_eq!(my_file, b"Old");
+
+        // A cancelled download reports what was acknowledged.
+        let my_file: Vec<u8> = [0x5A; 1024].to_vec();
+        let mut machine = Machine::new();
+        machine
+            .request_send_file(Instant::now(), String::from("ABCDE"), &my_file, &mut tx)
+            .unwrap();
+        for block in 0..2 {
+            let count = Ack::new(block).serialize(&mut rx);
+            machine
+                .process(Instant::now(), peer(), &rx[0..count], &mut tx)
+                .unwrap();
+        }
+        let summary = machine.cancel(String::from("Stop"), &mut tx).unwrap();
+        assert_eq!(summary.transfer_type, TransferType::Write);
+        assert_eq!(summary.state, TransferState::AwaitingAck(2));
+        assert_eq!(summary.bytes, 512);
+        assert_eq!(summary.blocks, 1);
+    }
+
+    #[test]
+    fn test_dally_after_final_ack() {
+        let mut my_file: Vec<u8> = Vec::new();
+        let mut tx = [0u8; MAX_PACKET_SIZE];
+        let mut rx = [0u8; MAX_PACKET_SIZE];
+        let start = Instant::now();
+        let mut machine = Machine::new();
+        machine.set_dally_duration(Duration::from_secs(3)).unwrap();
+        machine
+            .request_receive_file(start, String::from("ABCDE"), &mut my_file, &mut tx)
+            .unwrap();
+        let count = Data::new(1, &[0x5A; 100]).serialize(&mut rx);
+        let event = machine.process(start, peer(), &rx[0..count], &mut tx);
+        assert_eq!(
+            event,
+            Ok(Event::TransferComplete {
+                bytes: 100,
+                blocks: 1,
+                transmit: Some(4)
+            })
+        );
+        assert_eq!(machine.state(), TransferState::Dallying);
+        assert_eq!(
+            machine.set_dally_duration(Duration::ZERO),
+            Err(TftprsError::Busy)
+        );
+
+        // The final ack was lost, so the peer sends the final block again.
+        tx.fill(0);
+        let event = machine.process(start, peer(), &rx[0..count], &mut tx);
+        assert_eq!(event, Ok(Event::Transmit(4)));
+        assert_eq!(&tx[0..4], &[0x0, 0x4, 0x0, 0x1]);
+        let count = Ack::new(1).serialize(&mut rx);
+        let event = machine.process(start, peer(), &rx[0..count], &mut tx);
+        assert_eq!(event, Ok(Event::Ignored(IgnoreReason::Dallying)));
+
+        // The machine goes idle once the dally period is over.
+        let event = machine.handle_timeout(start + Duration::from_secs(1), &mut tx);
+        assert_eq!(event, Ok(Event::NeedTimer(start + Duration::from_secs(3))));
+        let event = machine.handle_timeout(start + Duration::from_secs(3), &mut tx);
+        assert_eq!(event, Ok(Event::DallyEnded));
+        assert_eq!(machine.state(), TransferState::Completed);
+        assert_eq!(machine.bytes_transferred(), 100);
+        let event = machine.handle_timeout(start + Duration::from_secs(4), &mut tx);
+        assert_eq!(event, Ok(Event::Ignored(IgnoreReason::Idle)));
+
+        // A message that arrives after the dally period also ends it, without an answer.
+        machine
+            .request_receive_file(start, String::from("ABCDE"), Vec::new(), &mut tx)
+            .unwrap();
+        let count = Data::new(1, &[0x5A; 100]).serialize(&mut rx);
+        machine
+            .process(start, peer(), &rx[0..count], &mut tx)
+            .unwrap();
+        let event = machine.process(
+            start + Duration::from_secs(3),
+            peer(),
+            &rx[0..count],
+            &mut tx,
+        );
+        assert_eq!(event, Ok(Event::DallyEnded));
+        assert_eq!(machine.state(), TransferState::Completed);
+        drop(machine);
+        assert_eq!(my_file, [0x5A; 100]);
+    }
+
+    #[test]
+    fn test_adaptive_timeout() {
+        let my_file: Vec<u8> = [0x5A; 2048].to_vec();
+        let mut tx = [0u8; MAX_PACKET_SIZE];
+        let mut rx = [0u8; MAX_PACKET_SIZE];
+        let start = Instant::now();
+        let mut machine = Machine::new();
+        machine.set_adaptive_timeout(true).unwrap();
+        assert_eq!(machine.retransmit_timeout(), DEFAULT_RETRANSMIT_TIMEOUT);
+        machine
+            .request_send_file(start, String::from("ABCDE"), &my_file, &mut tx)
+            .unwrap();
+        let count = Ack::new(0).serialize(&mut rx);
+        let now = start + Duration::from_millis(100);
+        machine
+            .process(now, peer(), &rx[0..count], &mut tx)
+            .unwrap();
+        assert_eq!(machine.rtt_stats().srtt, Some(Duration::from_millis(100)));
+        assert_eq!(machine.retransmit_timeout(), Duration::from_millis(300));
+
+        // The timeout doubles with each retransmission, and the reply to a retransmission is not measured. The
+        // timeout stays doubled until a round trip is measured again.
+        let now = now + Duration::from_millis(300);
+        let event = machine.handle_timeout(now, &mut tx).unwrap();
+        assert_eq!(event, Event::Transmit(516));
+        assert_eq!(machine.retransmit_timeout(), Duration::from_millis(600));
+        assert_eq!(
+            machine.poll_timeout(),
+            Some(now + Duration::from_millis(600))
+        );
+        let count = Ack::new(1).serialize(&mut rx);
+        machine
+            .process(now, peer(), &rx[0..count], &mut tx)
+            .unwrap();
+        assert_eq!(machine.rtt_stats().samples, 1);
+        assert_eq!(machine.retransmit_timeout(), Duration::from_millis(600));
+        let count = Ack::new(2).serialize(&mut rx);
+        machine
+            .process(
+                now + Duration::from_millis(20),
+                peer(),
+                &rx[0..count],
+                &mut tx,
+            )
+            .unwrap();
+        assert_eq!(machine.rtt_stats().samples, 2);
+        assert_eq!(machine.rtt_stats().latest, Some(Duration::from_millis(20)));
+        assert_eq!(machine.retransmit_timeout(), Duration::from_millis(320));
+    }
+
+    #[test]
+    fn test_adaptive_timeout_long_round_trip() {
+        // Twenty blocks and a short one
+        let my_file: Vec<u8> = [0x5A; 20 * DEFAULT_BLOCK_SIZE].to_vec();
+        let mut tx = [0u8; MAX_PACKET_SIZE];
+        let mut rx = [0u8; MAX_PACKET_SIZE];
+        let rtt = Duration::from_millis(1500);
+        let mut now = Instant::now();
+        let mut machine = Machine::new();
+        machine.set_adaptive_timeout(true).unwrap();
+        machine
+            .request_send_file(now, String::from("ABCDE"), &my_file, &mut tx)
+            .unwrap();
+
+        // Every ack arrives a round trip after the message it answers was first sent, and the message is
+        // retransmitted whenever the timer runs out in the meantime.
+        let mut retransmissions = 0;
+        let mut sent_at = now;
+        for block in 0..=21 {
+            let reply = sent_at + rtt;
+            while let Some(deadline) = machine.poll_timeout().filter(|deadline| *deadline < reply) {
+                now = deadline;
+                let event = machine.handle_timeout(now, &mut tx).unwrap();
+                assert!(event.transmit().is_some());
+                retransmissions += 1;
+            }
+            now = reply;
+            let count = Ack::new(block).serialize(&mut rx);
+            machine
+                .process(now, peer(), &rx[0..count], &mut tx)
+                .unwrap();
+            sent_at = now;
+        }
+        assert!(!machine.is_busy());
+
+        // The doubled timeout outlasts the round trip after the first retransmission. From then on, every round
+        // trip is measured, and nothing else is retransmitted.
+        assert_eq!(retransmissions, 1);
+        assert_eq!(machine.rtt_stats().samples, 20);
+        assert_eq!(machine.rtt_stats().srtt, Some(rtt));
+        assert!(machine.retransmit_timeout() > rtt);
+    }
+
+    #[test]
+    fn test_congestion_control() {
+        // Ten blocks and a short one
+        let my_file: Vec<u8> = [0x5A; 84].to_vec();
+        let mut tx = [0u8; MAX_PACKET_SIZE];
+        let mut rx = [0u8; MAX_PACKET_SIZE];
+        let start = Instant::now();
+
+        let mut machine = Machine::new();
+        machine.set_congestion_control(true).unwrap();
+        let request = Request::new(TransferType::Read, Mode::Binary, String::from("ABCDE"))
+            .unwrap()
+            .with_options(vec![
+                TransferOption::block_size(8),
+                TransferOption::window_size(4),
+            ])
+            .unwrap();
+        let count = request.serialize(&mut rx);
+        machine.listen_for_request(peer(), &rx[0..count]).unwrap();
+        machine
+            .accept_option(TransferOption::block_size(8))
+            .unwrap();
+        machine
+            .accept_option(TransferOption::window_size(4))
+            .unwrap();
+        machine.reply_send_file(start, &my_file, &mut tx).unwrap();
+
+        // Each ack is answered with the blocks of the next window, which grows by one block when the whole window
+        // was acknowledged, and halves after a partial ack or a timeout.
+        let mut window = |ack: Option<u16>, machine: &mut Machine, tx: &mut [u8]| {
+            match ack {
+                Some(block) => {
+                    let count = Ack::new(block).serialize(&mut rx);
+                    machine.process(start, peer(), &rx[0..count], tx).unwrap();
+                }
+                None => {
+                    machine
+                        .handle_timeout(start + Duration::from_secs(2), tx)
+                        .unwrap();
+                }
+            }
+            let mut blocks = vec![tx[3]];
+            while machine.poll_transmit(tx).unwrap().is_some() {
+                blocks.push(tx[3]);
+            }
+            blocks
+        };
+        // The ack at block 0 only completes the handshake, so the first window is a single block.
+        assert_eq!(window(Some(0), &mut machine, &mut tx), [1]);
+        assert_eq!(
+            machine.congestion_stats().map(|stats| stats.window),
+            Some(1)
+        );
+        assert_eq!(window(Some(1), &mut machine, &mut tx), [2, 3]);
+        assert_eq!(window(Some(3), &mut machine, &mut tx), [4, 5, 6]);
+        assert_eq!(window(Some(5), &mut machine, &mut tx), [6]);
+        assert_eq!(window(Some(6), &mut machine, &mut tx), [7, 8]);
+        assert_eq!(window(None, &mut machine, &mut tx), [7]);
+        assert_eq!(window(Some(7), &mut machine, &mut tx), [8, 9]);
+        assert_eq!(window(Some(9), &mut machine, &mut tx), [10, 11]);
+        let count = Ack::new(11).serialize(&mut rx);
+        let event = machine.process(start, peer(), &rx[0..count], &mut tx);
+        assert!(matches!(event, Ok(Event::TransferComplete { .. })));
+        assert_eq!(
+            machine.congestion_stats(),
+            Some(CongestionStats {
+                window: 3,
+                increases: 5,
+                decreases: 2,
+            })
+        );
+    }
+
+    #[test]
+    fn test_transfer_limits() {
+        let mut tx = [0u8; MAX_PACKET_SIZE];
+        let mut rx = [0u8; MAX_PACKET_SIZE];
+        let start = Instant::now();
+
+        // A peer that sends more than allowed is stopped, and the partial file is discarded.
+        let mut my_file: Vec<u8> = Vec::new();
+        let mut machine = Machine::new();
+        machine
+            .set_limits(TransferLimits {
+                max_bytes: Some(1000),
+                ..Default::default()
+            })
+            .unwrap();
+        machine
+            .request_receive_file(start, String::from("ABCDE"), &mut my_file, &mut tx)
+            .unwrap();
+        let count = Data::new(1, &[0x5A; DEFAULT_BLOCK_SIZE]).serialize(&mut rx);
+        machine
+            .process(start, peer(), &rx[0..count], &mut tx)
+            .unwrap();
+        let count = Data::new(2, &[0x5A; DEFAULT_BLOCK_SIZE]).serialize(&mut rx);
+        let result = machine.process(start, peer(), &rx[0..count], &mut tx);
+        assert_eq!(result, Err(TftprsError::LimitExceeded(43)));
+        assert_eq!(tx[3], ErrorCode::DiskFull as u8);
+        assert_eq!(machine.state(), TransferState::Failed);
+        drop(machine);
+        assert!(my_file.is_empty());
+
+        // A transfer that takes too long is ended, even if the peer keeps it going.
+        let my_file: Vec<u8> = [0x5A; 2048].to_vec();
+        let mut machine = Machine::new();
+        machine
+            .set_limits(TransferLimits {
+                max_duration: Some(Duration::from_secs(5)),
+                ..Default::default()
+            })
+            .unwrap();
+        machine
+            .request_send_file(start, String::from("ABCDE"), &my_file, &mut tx)
+            .unwrap();
+        for block in 0..2 {
+            let count = Ack::new(block).serialize(&mut rx);
+            let now = start + Duration::from_secs(4) * u32::from(block);
+            machine
+                .process(now, peer(), &rx[0..count], &mut tx)
+                .unwrap();
+        }
+        assert_eq!(machine.poll_timeout(), Some(start + Duration::from_secs(5)));
+        let result = machine.handle_timeout(start + Duration::from_secs(5), &mut tx);
+        assert!(matches!(result, Err(TftprsError::LimitExceeded(_))));
+        assert_eq!(tx[3], ErrorCode::Undefined as u8);
+        assert_eq!(machine.state(), TransferState::Failed);
+
+        // A server counts from its reply, not from when the request came in.
+        machine
+            .set_limits(TransferLimits {
+                max_duration: Some(Duration::from_secs(5)),
+                ..Default::default()
+            })
+            .unwrap();
+        let request =
+            Request::new(TransferType::Read, Mode::Binary, String::from("ABCDE")).unwrap();
+        let count = request.serialize(&mut rx);
+        machine.listen_for_request(peer(), &rx[0..count]).unwrap();
+        let reply = start + Duration::from_secs(10);
+        machine.reply_send_file(reply, &my_file, &mut tx).unwrap();
+        let count = Ack::new(1).serialize(&mut rx);
+        machine
+            .process(
+                reply + Duration::from_secs(4),
+                peer(),
+                &rx[0..count],
+                &mut tx,
+            )
+            .unwrap();
+        assert!(machine.is_busy());
+        assert_eq!(machine.poll_timeout(), Some(reply + Duration::from_secs(5)));
+
+        // Retransmissions count against the budget, even after progress was made.
+        let mut machine = Machine::new();
+        machine
+            .set_limits(TransferLimits {
+                max_retries: Some(1),
+                ..Default::default()
+            })
+            .unwrap();
+        machine
+            .request_send_file(start, String::from("ABCDE"), &my_file, &mut tx)
+            .unwrap();
+        let now = start + Duration::from_secs(1);
+        let event = machine.handle_timeout(now, &mut tx);
+        assert!(event.unwrap().transmit().is_some());
+        let count = Ack::new(0).serialize(&mut rx);
+        machine
+            .process(now, peer(), &rx[0..count], &mut tx)
+            .unwrap();
+        let result = machine.handle_timeout(now + Duration::from_secs(1), &mut tx);
+        assert!(matches!(result, Err(TftprsError::LimitExceeded(_))));
+        assert_eq!(machine.state(), TransferState::Failed);
+    }
+
+    #[test]
+    fn test_machine_config() {
+        let my_file: Vec<u8> = [0x5A; 2000].to_vec();
+        let mut tx = [0u8; MAX_PACKET_SIZE];
+        let mut rx = [0u8; MAX_PACKET_SIZE];
+        let config = MachineConfig::default()
+            .with_accept(OptionPolicy {
+                max_block_size: Some(1024),
+                transfer_size: true,
+                ..Default::default()
+            })
+            .with_max_retries(2)
+            .with_dally_duration(Duration::from_secs(1));
+        let mut machine = Machine::with_config(config.clone()).unwrap();
+        let count = Request::new(TransferType::Read, Mode::Binary, String::from("ABCDE"))
+            .unwrap()
+            .with_options(vec![
+                TransferOption::block_size(1428),
+                TransferOption::transfer_size(0),
+                TransferOption::timeout(3),
+            ])
+            .unwrap()
+            .serialize(&mut rx);
+        machine.listen_for_request(peer(), &rx[0..count]).unwrap();
+        assert_eq!(
+            machine.negotiated_options(),
+            [
+                TransferOption::block_size(1024),
+                TransferOption::transfer_size(0)
+            ]
+        );
+        assert_eq!(machine.configure(config), Err(TftprsError::Busy));
+        let count = machine
+            .reply_send_file(Instant::now(), &my_file, &mut tx)
+            .unwrap();
+        assert_eq!(
+            &tx[0..count],
+            b"\x00\x06blksize\x001024\x00tsize\x002000\x00"
+        );
+        assert_eq!(machine.block_size(), 1024);
+
+        // Invalid option proposals are refused.
+        let config = MachineConfig::default().with_options(vec![TransferOption::block_size(1)]);
+        assert!(matches!(
+            Machine::with_config(config),
+            Err(TftprsError::BadRequestAttempted)
+        ));
+    }
+
+    #[test]
+    fn test_typed_roles() {
+        let my_file: Vec<u8> = (0..1500).map(|i| i as u8).collect();
+        let mut uploaded: Vec<u8> = Vec::new();
+        let mut downloaded: Vec<u8> = Vec::new();
+        let mut client_tx = [0u8; MAX_PACKET_SIZE];
+        let mut server_tx = [0u8; MAX_PACKET_SIZE];
+        let now = Instant::now();
+        let client = Client::new(
+            MachineConfig::default().with_options(vec![TransferOption::transfer_size(0)]),
+        );
+        let server = Server::new(MachineConfig::default().with_accept(OptionPolicy {
+            transfer_size: true,
+            ..Default::default()
+        }));
+
+        // The client uploads a file, which the server takes in.
+        let (mut put, mut count) = client
+            .put(now, String::from("ABCDE"), &my_file, &mut client_tx)
+            .unwrap();
+        let IncomingRequest::Write(request) = server
+            .listen(peer(), &client_tx[0..count], &mut server_tx)
+            .unwrap()
+        else {
+            panic!("A write request is expected");
+        };
+        assert_eq!(request.filename(), "ABCDE");
+        assert_eq!(request.transfer_size(), Some(1500));
+        let (mut write, mut reply) = request.accept(now, &mut uploaded, &mut server_tx).unwrap();
+        assert_eq!(write.state(), TransferState::AwaitingData(1));
+        loop {
+            let event = put
+                .process(now, peer(), &server_tx[0..reply], &mut client_tx)
+                .unwrap();
+            count = event.transmit().unwrap();
+            let event = write
+                .process(now, peer(), &client_tx[0..count], &mut server_tx)
+                .unwrap();
+            reply = event.transmit().unwrap();
+            if let Event::TransferComplete { bytes, .. } = event {
+                assert_eq!(bytes, 1500);
+                break;
+            }
+        }
+        let event = put
+            .process(now, peer(), &server_tx[0..reply], &mut client_tx)
+            .unwrap();
+        assert!(matches!(event, Event::TransferComplete { bytes: 1500, .. }));
+        drop(write);
+        assert_eq!(uploaded, my_file);
+
+        // The client downloads it again.
+        let (mut get, count) = client
+            .get(now, String::from("ABCDE"), &mut downloaded, &mut client_tx)
+            .unwrap();
+        let IncomingRequest::Read(request) = server
+            .listen(peer(), &client_tx[0..count], &mut server_tx)
+            .unwrap()
+        else {
+            panic!("A read request is expected");
+        };
+        let (mut read, mut reply) = request.accept(now, &uploaded, &mut server_tx).unwrap();
+        loop {
+            let event = get
+                .process(now, peer(), &server_tx[0..reply], &mut client_tx)
+                .unwrap();
+            let count = event.transmit().unwrap();
+            let event = read
+                .process(now, peer(), &client_tx[0..count], &mut server_tx)
+                .unwrap();
+            match event {
+                Event::Transmit(count) => reply = count,
+                Event::TransferComplete { bytes, .. } => {
+                    assert_eq!(bytes, 1500);
+                    break;
+                }
+                _ => panic!("Unexpected event {event:?}"),
+            }
+        }
+        drop(get);
+        assert_eq!(downloaded, my_file);
+
+        // Anything other than a request is turned away.
+        let count = Ack::new(0).serialize(&mut client_tx);
+        assert!(matches!(
+            server.listen(peer(), &client_tx[0..count], &mut server_tx),
+            Err(TftprsError::NoConnection)
+        ));
+    }
+
+    #[test]
+    fn test_typed_server_requests() {
+        let mut tx = [0u8; MAX_PACKET_SIZE];
+        let mut rx = [0u8; MAX_PACKET_SIZE];
+        let mut delivered: Vec<(String, Vec<u8>)> = Vec::new();
+        let now = Instant::now();
+        let server = Server::new(MachineConfig::default());
+
+        // Mail cannot be read.
+        let request = b"\x00\x01postmaster\x00mail\x00";
+        assert_eq!(
+            server.listen(peer(), request, &mut tx).map(|_| ()),
+            Err(TftprsError::IllegalOperation(24))
+        );
+        assert_eq!(&tx[0..24], b"\x00\x05\x00\x04Mail cannot be read\x00");
+
+        // A request that cannot be accepted is handed back, and can still be rejected.
+        let count = Request::new(TransferType::Write, Mode::Binary, String::from("ABCDE"))
+            .unwrap()
+            .serialize(&mut rx);
+        let IncomingRequest::Write(request) =
+            server.listen(peer(), &rx[0..count], &mut tx).unwrap()
+        else {
+            panic!("A write request is expected");
+        };
+        let Err(Unanswered::Pending { request, error }) =
+            request.accept(now, Vec::new(), &mut tx[0..2])
+        else {
+            panic!("The request is expected back");
+        };
+        assert_eq!(
+            error,
+            TftprsError::BufferTooSmall {
+                needed: 4,
+                available: 2
+            }
+        );
+        assert!(request.is_busy());
+        assert_eq!(
+            request
+                .reject(
+                    ErrorCode::FileAlreadyExists,
+                    String::from("Exists"),
+                    &mut tx
+                )
+                .map_err(Unanswered::into_error),
+            Ok(11)
+        );
+        assert_eq!(tx[3], ErrorCode::FileAlreadyExists as u8);
+
+        // A request the machine refuses by itself is over, and only the error it wrote out is left to send.
+        let limited = Server::new(MachineConfig::default().with_limits(TransferLimits {
+            max_bytes: Some(4096),
+            ..Default::default()
+        }));
+        let count = Request::new(TransferType::Write, Mode::Binary, String::from("ABCDE"))
+            .unwrap()
+            .with_options(vec![TransferOption::transfer_size(10000)])
+            .unwrap()
+            .serialize(&mut rx);
+        let IncomingRequest::Write(request) =
+            limited.listen(peer(), &rx[0..count], &mut tx).unwrap()
+        else {
+            panic!("A write request is expected");
+        };
+        let Err(Unanswered::Ended(TftprsError::LimitExceeded(count))) =
+            request.accept(now, Vec::new(), &mut tx)
+        else {
+            panic!("The request is expected to end");
+        };
+        assert_eq!(count, 43);
+        assert_eq!(tx[1], OpCode::Error as u8);
+        assert_eq!(tx[3], ErrorCode::DiskFull as u8);
+
+        // Mail comes in as its own kind of request.
+        {
+            let request = b"\x00\x02postmaster\x00mail\x00";
+            let IncomingRequest::Mail(request) = server.listen(peer(), request, &mut tx).unwrap()
+            else {
+                panic!("A mail request is expected");
+            };
+            assert_eq!(request.recipient(), "postmaster");
+            let (mut mail, count) = request
+                .accept(
+                    now,
+                    |recipient: &str, message: &[u8]| {
+                        delivered.push((recipient.to_string(), message.to_vec()));
+                    },
+                    &mut tx,
+                )
+                .map_err(Unanswered::into_error)
+                .unwrap();
+            assert_eq!(count, 4);
+            let count = Data::new(1, b"Hello\r\n").serialize(&mut rx);
+            let event = mail.process(now, peer(), &rx[0..count], &mut tx).unwrap();
+            assert!(matches!(event, Event::TransferComplete { bytes: 6, .. }));
+        }
+        assert_eq!(
+            delivered,
+            vec![(String::from("postmaster"), b"Hello\n".to_vec())]
+        );
+    }
 }
@@ -0,0 +1,20 @@
+//! Limits on a single transfer
+
+use std::time::Duration;
+
+/// Bounds on what a single transfer may cost the host, so that a peer cannot exhaust its memory or keep a transfer
+/// alive forever. No limit applies by default. When a limit is hit, the machine writes an error for the peer, and the
+/// transfer fails with `TftprsError::LimitExceeded`.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize), serde(default))]
+pub struct TransferLimits {
+    /// The largest file the host is willing to receive. A peer that declares a larger transfer size is refused
+    /// before any data is transferred, and one that sends more data is stopped with a disk full error.
+    pub max_bytes: Option<u64>,
+    /// The longest a transfer may take, counted from the request the host sends, or from its reply to a request from
+    /// the peer. The time the host takes to decide on a request, such as to open the file, does not count.
+    #[cfg_attr(feature = "serde", serde(with = "crate::config::optional_seconds"))]
+    pub max_duration: Option<Duration>,
+    /// The number of retransmissions allowed over the whole transfer, on top of the limit on consecutive ones.
+    pub max_retries: Option<u32>,
+}
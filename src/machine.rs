@@ -1,18 +1,69 @@
 //! Definition of the TFTP protocol state machine / message engine
 
-use crate::constants::BINARY_MODE;
+use crate::constants::DEFAULT_BLOCK_SIZE;
 use crate::constants::MAX_PACKET_SIZE;
-use crate::constants::TEXT_MODE;
+use crate::constants::Rollover;
+use crate::constants::Strictness;
 use crate::constants::TransferType;
-use crate::constants::{ErrorCode, FIXED_DATA_BYTES, MAX_DATA_SIZE, Mode, OpCode};
+use crate::constants::{ErrorCode, FIXED_DATA_BYTES, Mode, OpCode};
 
-use crate::errors::TftprsError;
+use crate::errors::{PacketError, TftprsError};
+
+use crate::event::{Event, IgnoreReason};
+
+use crate::state::{TransferState, TransferSummary};
+
+use crate::file::{FileSink, FileSource};
+
+use crate::options::{BLOCK_SIZE_OPTION, ROLLOVER_OPTION, TIMEOUT_OPTION};
+use crate::options::{TRANSFER_SIZE_OPTION, WINDOW_SIZE_OPTION};
+use crate::options::{TransferOption, find_option};
+use crate::options::{parse_block_size, parse_rollover, parse_timeout};
+use crate::options::{parse_transfer_size, parse_window_size};
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
 
 use crate::serial::Serial;
 use crate::serial::{Ack, ErrorResponse};
-use crate::serial::{Data, Request};
+use crate::serial::{Data, OptionAck, Request};
+
+use crate::netascii::{Decoder, Encoder};
+
+use crate::packet::{Options, Packet};
+
+use crate::timer::{RetransmitTimer, RttStats};
+
+use crate::congestion::{CongestionStats, CongestionWindow};
+
+use crate::limits::TransferLimits;
 
-const TERMINATOR_BYTE: u8 = 0x0;
+use crate::config::{MachineConfig, OptionPolicy};
+
+/// The host's callback that takes the recipient and the message of a mail request.
+type MailSink<'a> = Box<dyn FnMut(&str, &[u8]) + Send + 'a>;
+
+/// A mail message being received, and the host's callback that takes it once it is complete.
+struct MailDelivery<'a> {
+    // The recipient named in the request.
+    recipient: String,
+    // The message received so far.
+    message: Vec<u8>,
+    // Takes the recipient and the message.
+    sink: MailSink<'a>,
+}
+
+impl fmt::Debug for MailDelivery<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MailDelivery")
+            .field("recipient", &self.recipient)
+            .field("message", &self.message)
+            .finish_non_exhaustive()
+    }
+}
 
 /// This machine operates as the transfer engine for the protocol. It provides an interface for
 /// initiating transfers and for handling transfer requests. It will process incoming messages and
@@ -20,21 +71,113 @@ const TERMINATOR_BYTE: u8 = 0x0;
 ///
 /// The machine is synchronous and network-agnostic. Therefore, it is up to the host to:
 ///  * Perform actual network send and receive operations, and provide the byte buffers for receiving and transmitting messages.
-///  * Handle timing in between messages per the advice in the RFC.
-///  * Respond to remote requests with the file for reading or the destination file for writing.
-///  * Provide a reference to the target file that lives as long as this machine does. In the case of mutable reference, it must be exclusively held by the machine.
+///    A received message is passed as a slice of exactly the bytes of the datagram. A transmit buffer of
+///    `MAX_PACKET_SIZE` holds any message, but a smaller one will do if it fits the negotiated block size.
+///  * Act on the `Event` returned for each message, and drain any further outgoing messages with `poll_transmit()`
+///    after each reply, since a windowed transfer sends several data packets at once.
+///  * Keep time. The host passes in the current time along with each message, waits until `poll_timeout()`
+///    for the next message, and calls `handle_timeout()` when that time passes to retransmit the last message.
+///  * Pass in the source address of each received message, and send replies to `peer()`. Unknown transfer ID errors
+///    go back to the address of the message that caused them instead.
+///  * Respond to remote requests with the file for reading or the destination file for writing. Requests in mail
+///    mode are either taken by a mail sink or rejected.
+///  * Provide the file to send as a `FileSource`, and the destination of a received file as a `FileSink`. Either may
+///    borrow from the host for as long as the machine holds it. The file is streamed one block at a time, so a source
+///    of unknown length, such as a pipe, ends with the first short block. A machine with owned sources and sinks is
+///    `'static + Send`, and one `Arc<[u8]>`, or `Bytes` with the `bytes` feature, can be served to many peers at once.
 #[derive(Debug, Default)]
 pub struct Machine<'a> {
     // The active transfer type. The machine is considered idle if this is None.
     transfer_type: Option<TransferType>,
-    // The mutable target file for a write request.
-    incoming_file: Option<&'a mut Vec<u8>>,
-    // The immutable source file for a read request.
-    outgoing_file: Option<&'a Vec<u8>>,
+    // The destination of the file when reading.
+    sink: Option<Box<dyn FileSink + Send + 'a>>,
+    // The file to send when writing.
+    source: Option<Box<dyn FileSource + Send + 'a>>,
     // The mode to be sent in a request, or captured from a request.
     mode: Mode,
-    // The current block to be sent in the next datagram, or to be acknowledged in an incoming request or datagram.
-    block: u16,
+    // When writing, the first block of the current window, which is the block after the last one acknowledged.
+    // When reading, the next block expected from the peer. Blocks are counted from the start of the transfer,
+    // without regard to the rollover of the block field on the wire.
+    block: u64,
+    // The options the host proposes in its own requests.
+    options: Vec<TransferOption>,
+    // The options the remote peer proposed in its request.
+    requested_options: Vec<TransferOption>,
+    // Which options the remote peer proposed are accepted without the host's involvement.
+    option_policy: OptionPolicy,
+    // The options in effect for the active transfer, either accepted by the host or acknowledged by the peer.
+    negotiated_options: Vec<TransferOption>,
+    // Whether the host sent a request with options and has not yet seen the first reply.
+    awaiting_oack: bool,
+    // Whether the peer acknowledged the options of the host's request. Until block 1 gets through, another option
+    // acknowledgement is a retransmission.
+    oack_received: bool,
+    // The number of data bytes in a full block.
+    block_size: usize,
+    // The retransmission interval, if one was negotiated.
+    timeout: Option<Duration>,
+    // The size of the file, if the sender declared it.
+    transfer_size: Option<u64>,
+    // The bounds on the size, duration and retransmissions of a transfer.
+    limits: TransferLimits,
+    // When the host sent its request or its reply to the peer's request, which starts the clock on the duration.
+    started: Option<Instant>,
+    // The number of retransmissions in the active transfer.
+    total_retries: u32,
+    // The number of blocks sent before waiting for an ack.
+    window_size: u16,
+    // Whether the sender limits its window to what the link appears to carry.
+    congestion_control: bool,
+    // When writing, the congestion window that limits the window size.
+    congestion: CongestionWindow,
+    // When writing, the next block of the current window to transmit.
+    next_block: u64,
+    // When writing, the last block of the current window.
+    window_end: u64,
+    // When writing, the final (short) block of the file, once it has been sent.
+    last_block: Option<u64>,
+    // When writing, the offset in the file at the end of the final block, once it has been sent.
+    end_offset: u64,
+    // The number of bytes of the file sent and acknowledged, or received.
+    bytes: u64,
+    // The number of blocks sent and acknowledged, or received.
+    blocks: u64,
+    // How the last transfer ended, until the machine is reset or a new transfer starts.
+    outcome: Option<TransferState>,
+    // How long a receiver stays around after the final ack, in case the peer sends the final block again.
+    dally_duration: Duration,
+    // Whether the file was received, and the final ack is kept to answer a retransmission of the final block.
+    dallying: bool,
+    // When reading, the number of blocks received since the last ack.
+    window_count: u16,
+    // When reading, whether the last block received in order was already acknowledged due to a gap.
+    gap_acked: bool,
+    // The block number that follows block 65535, unless the peers negotiated otherwise.
+    rollover: Rollover,
+    // How closely remote peers are held to the RFC.
+    strictness: Strictness,
+    // Whether messages from the peer that break the protocol are answered with an error.
+    reply_to_violations: bool,
+    // The block number that follows block 65535, as negotiated with the rollover option.
+    negotiated_rollover: Option<Rollover>,
+    // The timer for retransmitting the last message.
+    timer: RetransmitTimer,
+    // The recipient named in a mail request from the remote peer.
+    recipient: Option<String>,
+    // The mail message being received, if the host accepted a mail request.
+    mail: Option<MailDelivery<'a>>,
+    // When writing in netascii, the position in the file at the start of each block from the window base up to the
+    // next block to encode. The encoded blocks differ in length from the file, so they cannot be located directly.
+    encoders: VecDeque<Encoder>,
+    // When reading in netascii, the translation of the data received so far.
+    decoder: Decoder,
+    // Scratch space for translating a netascii block, reused so that each block does not allocate.
+    netascii_buffer: Vec<u8>,
+    // The address and port of the remote peer, which identify its end of the transfer (its TID).
+    peer: Option<SocketAddr>,
+    // The last message other than data, kept for retransmission. When writing data, the window is regenerated
+    // from the file instead.
+    last_sent: Vec<u8>,
 }
 
 impl<'a> Machine<'a> {
@@ -44,12 +187,65 @@ impl<'a> Machine<'a> {
         me
     }
 
+    /// Creates a machine with the given settings.
+    pub fn with_config(config: MachineConfig) -> Result<Machine<'a>, TftprsError> {
+        let mut me = Self::new();
+        me.configure(config)?;
+        Ok(me)
+    }
+
+    /// Applies all of the given settings. This can only be done when no transfer is being performed.
+    pub fn configure(&mut self, config: MachineConfig) -> Result<(), TftprsError> {
+        self.set_options(config.options)?;
+        self.set_mode(config.mode)?;
+        self.set_option_policy(config.accept)?;
+        self.set_retransmit_timeout(config.retransmit_timeout)?;
+        self.set_adaptive_timeout(config.adaptive_timeout)?;
+        self.set_max_retries(config.max_retries)?;
+        self.set_dally_duration(config.dally_duration)?;
+        self.set_strictness(config.strictness)?;
+        self.set_reply_to_violations(config.reply_to_violations)?;
+        self.set_rollover(config.rollover)?;
+        self.set_congestion_control(config.congestion_control)?;
+        self.set_limits(config.limits)
+    }
+
     /// Resets the machine to an idle state.
     pub fn reset(&mut self) {
         self.transfer_type = None;
-        self.incoming_file = None;
-        self.outgoing_file = None;
+        self.sink = None;
+        self.source = None;
         self.block = 0;
+        self.requested_options.clear();
+        self.negotiated_options.clear();
+        self.awaiting_oack = false;
+        self.oack_received = false;
+        self.block_size = DEFAULT_BLOCK_SIZE;
+        self.timeout = None;
+        self.transfer_size = None;
+        self.window_size = 1;
+        self.congestion = CongestionWindow::default();
+        self.next_block = 0;
+        self.window_end = 0;
+        self.last_block = None;
+        self.end_offset = 0;
+        self.bytes = 0;
+        self.blocks = 0;
+        self.outcome = None;
+        self.dallying = false;
+        self.started = None;
+        self.total_retries = 0;
+        self.window_count = 0;
+        self.gap_acked = false;
+        self.negotiated_rollover = None;
+        self.recipient = None;
+        self.mail = None;
+        self.encoders.clear();
+        self.encoders.push_back(Encoder::default());
+        self.decoder = Decoder::default();
+        self.timer.stop();
+        self.peer = None;
+        self.last_sent.clear();
     }
 
     /// Sets the file mode. This can only be done when no transfer is being performed.
@@ -61,6 +257,160 @@ impl<'a> Machine<'a> {
         Ok(())
     }
 
+    /// Sets the options to propose in requests made by the host. This can only be done when no transfer is being performed.
+    /// The peer may acknowledge some or all of them with an OACK, or ignore them altogether.
+    pub fn set_options(&mut self, options: Vec<TransferOption>) -> Result<(), TftprsError> {
+        if self.is_busy() {
+            return Err(TftprsError::Busy);
+        }
+        if !options.iter().all(TransferOption::is_valid) {
+            return Err(TftprsError::BadRequestAttempted);
+        }
+        self.options = options;
+        Ok(())
+    }
+
+    /// Sets which options requested by a remote peer are accepted as soon as the request arrives. The host may still
+    /// accept others, or change the accepted ones, with `accept_option()` before it replies.
+    /// This can only be done when no transfer is being performed.
+    pub fn set_option_policy(&mut self, policy: OptionPolicy) -> Result<(), TftprsError> {
+        if self.is_busy() {
+            return Err(TftprsError::Busy);
+        }
+        self.option_policy = policy;
+        Ok(())
+    }
+
+    /// Sets the block number that follows block 65535 when the peers do not negotiate it with the rollover option.
+    /// This can only be done when no transfer is being performed.
+    pub fn set_rollover(&mut self, rollover: Rollover) -> Result<(), TftprsError> {
+        if self.is_busy() {
+            return Err(TftprsError::Busy);
+        }
+        self.rollover = rollover;
+        Ok(())
+    }
+
+    /// Sets how closely remote peers are held to the RFC. This can only be done when no transfer is being performed.
+    pub fn set_strictness(&mut self, strictness: Strictness) -> Result<(), TftprsError> {
+        if self.is_busy() {
+            return Err(TftprsError::Busy);
+        }
+        self.strictness = strictness;
+        Ok(())
+    }
+
+    /// Sets whether a message from the peer that breaks the protocol, such as a malformed packet, a request in the
+    /// middle of a transfer, or data sent to the sending side, is answered with an illegal operation error. The
+    /// transfer then ends, and the error is reported with `TftprsError::IllegalOperation`. Otherwise, the message is
+    /// only reported to the host, and the transfer carries on. This can only be done when no transfer is being
+    /// performed.
+    pub fn set_reply_to_violations(&mut self, reply: bool) -> Result<(), TftprsError> {
+        if self.is_busy() {
+            return Err(TftprsError::Busy);
+        }
+        self.reply_to_violations = reply;
+        Ok(())
+    }
+
+    /// Sets the interval after which an unanswered message is retransmitted, unless the peers negotiate a timeout.
+    /// When the timeout adapts, this is the estimate until the first round trip is measured. This can only be done when no transfer is being performed.
+    pub fn set_retransmit_timeout(&mut self, timeout: Duration) -> Result<(), TftprsError> {
+        if self.is_busy() {
+            return Err(TftprsError::Busy);
+        }
+        self.timer.interval = timeout;
+        Ok(())
+    }
+
+    /// Sets whether the retransmission timeout adapts to the measured round trip time, starting from the configured
+    /// interval and backing off exponentially while retransmitting. A timeout negotiated with the peer stays fixed.
+    /// This can only be done when no transfer is being performed.
+    pub fn set_adaptive_timeout(&mut self, adaptive: bool) -> Result<(), TftprsError> {
+        if self.is_busy() {
+            return Err(TftprsError::Busy);
+        }
+        self.timer.adaptive = adaptive;
+        Ok(())
+    }
+
+    /// Sets whether the sender adapts its window to losses, starting from a single block and growing up to the
+    /// negotiated window size. This can only be done when no transfer is being performed.
+    pub fn set_congestion_control(&mut self, enabled: bool) -> Result<(), TftprsError> {
+        if self.is_busy() {
+            return Err(TftprsError::Busy);
+        }
+        self.congestion_control = enabled;
+        Ok(())
+    }
+
+    /// Sets the number of consecutive retransmissions after which the transfer fails.
+    /// This can only be done when no transfer is being performed.
+    pub fn set_max_retries(&mut self, retries: u32) -> Result<(), TftprsError> {
+        if self.is_busy() {
+            return Err(TftprsError::Busy);
+        }
+        self.timer.max_retries = retries;
+        Ok(())
+    }
+
+    /// Sets how long the host keeps acknowledging the final block after it received a file, in case the final ack was
+    /// lost and the peer sends the block again. The default of zero ends the transfer right away. Otherwise, the end of
+    /// the dally period is reported with `Event::DallyEnded`.
+    /// This can only be done when no transfer is being performed.
+    pub fn set_dally_duration(&mut self, duration: Duration) -> Result<(), TftprsError> {
+        if self.is_busy() {
+            return Err(TftprsError::Busy);
+        }
+        self.dally_duration = duration;
+        Ok(())
+    }
+
+    /// Sets the bounds on the size, duration and retransmissions of each transfer.
+    /// This can only be done when no transfer is being performed.
+    pub fn set_limits(&mut self, limits: TransferLimits) -> Result<(), TftprsError> {
+        if self.is_busy() {
+            return Err(TftprsError::Busy);
+        }
+        self.limits = limits;
+        Ok(())
+    }
+
+    /// The bounds on the size, duration and retransmissions of each transfer.
+    pub fn limits(&self) -> TransferLimits {
+        self.limits
+    }
+
+    /// The options the remote peer proposed in the request it sent.
+    pub fn requested_options(&self) -> &[TransferOption] {
+        &self.requested_options
+    }
+
+    /// The options in effect for the active transfer.
+    pub fn negotiated_options(&self) -> &[TransferOption] {
+        &self.negotiated_options
+    }
+
+    /// Accepts an option that the remote peer requested. This must be done after `listen_for_request` and before
+    /// replying. The value may differ from the requested one in order to clamp it. Any requested option that is not
+    /// accepted is rejected by omitting it from the option acknowledgement.
+    ///
+    /// A block size may only be clamped down from the requested size, and a timeout may not be changed.
+    /// When sending a file, the machine fills in the transfer size itself.
+    pub fn accept_option(&mut self, option: TransferOption) -> Result<(), TftprsError> {
+        if !self.is_busy() {
+            return Err(TftprsError::NoConnection);
+        }
+        match find_option(&self.requested_options, &option.name) {
+            Some(requested) if option.answers(requested) => {}
+            _ => return Err(TftprsError::BadRequestAttempted),
+        }
+        self.negotiated_options
+            .retain(|accepted| !accepted.is(&option.name));
+        self.negotiated_options.push(option);
+        Ok(())
+    }
+
     /// Indicates whether a transfer is being performed.
     pub fn is_busy(&self) -> bool {
         self.transfer_type.is_some()
@@ -79,32 +429,127 @@ impl<'a> Machine<'a> {
         self.mode
     }
 
+    /// The number of data bytes in a full block for the active transfer. This is 512 bytes unless the
+    /// peers negotiated otherwise.
+    pub fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    /// The retransmission interval negotiated for the active transfer, if any. The host should use it for its
+    /// retransmission timer in place of its own default.
+    pub fn timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
+
+    /// The size of the file being transferred, if the sending side declared it with the transfer size option.
+    pub fn transfer_size(&self) -> Option<u64> {
+        self.transfer_size
+    }
+
+    /// The number of consecutive data packets sent per ack for the active transfer. This is 1 unless the peers
+    /// negotiated otherwise.
+    pub fn window_size(&self) -> u16 {
+        self.window_size
+    }
+
+    /// How closely remote peers are held to the RFC.
+    pub fn strictness(&self) -> Strictness {
+        self.strictness
+    }
+
+    /// The block number that follows block 65535 in the active transfer.
+    pub fn rollover(&self) -> Rollover {
+        self.negotiated_rollover.unwrap_or(self.rollover)
+    }
+
+    /// The recipient of a mail request from the remote peer, which takes the place of the filename.
+    pub fn recipient(&self) -> Option<&str> {
+        self.recipient.as_deref()
+    }
+
+    /// The address of the remote peer in the active transfer, once it is known. When the host made the request, this
+    /// is the address that the first reply came from, which usually differs from the address the request was sent to.
+    pub fn peer(&self) -> Option<SocketAddr> {
+        self.peer
+    }
+
+    /// The time at which the last message is due for retransmission, or the transfer runs out of time, if the machine
+    /// is waiting on the peer.
+    pub fn poll_timeout(&self) -> Option<Instant> {
+        let deadline = self.timer.deadline()?;
+        Some(match self.transfer_deadline() {
+            Some(limit) if !self.dallying => deadline.min(limit),
+            _ => deadline,
+        })
+    }
+
+    /// The phase of the active transfer, or how the last one ended.
+    pub fn state(&self) -> TransferState {
+        let Some(transfer_type) = self.transfer_type else {
+            return self.outcome.unwrap_or(TransferState::Idle);
+        };
+        if self.dallying {
+            return TransferState::Dallying;
+        }
+        let has_file = match transfer_type {
+            TransferType::Write => self.source.is_some(),
+            TransferType::Read => self.sink.is_some() || self.mail.is_some(),
+        };
+        if !has_file {
+            TransferState::RequestReceived
+        } else if self.peer.is_none() {
+            TransferState::RequestSent
+        } else if transfer_type == TransferType::Write {
+            TransferState::AwaitingAck(self.window_end)
+        } else {
+            TransferState::AwaitingData(self.block)
+        }
+    }
+
+    /// The number of bytes of the file that were sent and acknowledged, or received, in the active transfer or the
+    /// last one. In netascii mode, this counts the bytes of the file on the host rather than on the wire.
+    pub fn bytes_transferred(&self) -> u64 {
+        self.bytes
+    }
+
+    /// The number of blocks that were sent and acknowledged, or received, in the active transfer or the last one.
+    pub fn blocks_transferred(&self) -> u64 {
+        self.blocks
+    }
+
+    /// The number of times the last message has been retransmitted without an answer.
+    pub fn retries(&self) -> u32 {
+        self.timer.retries()
+    }
+
     /// Sends a request to the remote peer to send / write a file out to that peer.
     pub fn request_send_file(
         &mut self,
+        now: Instant,
         filename: String,
-        file: &'a Vec<u8>,
-        outgoing: &mut [u8; MAX_PACKET_SIZE],
+        file: impl FileSource + Send + 'a,
+        outgoing: &mut [u8],
     ) -> Result<usize, TftprsError> {
         // Do not send a request if a transaction is already taking place.
         if self.is_busy() {
             return Err(TftprsError::Busy);
         }
-        // Do not allow files that are too large for the block field.
-        if file.len() > u16::MAX as usize * MAX_DATA_SIZE {
-            return Err(TftprsError::BadRequestAttempted);
-        }
+        self.reset();
         // Expect an ack at block 0
-        self.block = 0;
-        if let Ok(request) = Request::new(TransferType::Write, self.mode, filename) {
-            let count = request.serialize(outgoing);
-            if request.serialize(outgoing) > 0 {
-                self.outgoing_file = Some(file);
-                self.transfer_type = Some(TransferType::Write);
-                Ok(count)
-            } else {
-                Err(TftprsError::BadRequestAttempted)
-            }
+        self.block = 1;
+        self.window_end = 0;
+        let options = self.request_options(file.len());
+        let awaiting_oack = !options.is_empty();
+        if let Ok(request) = Request::new(TransferType::Write, self.mode, filename)
+            .and_then(|request| request.with_options(options))
+        {
+            let count = Self::write_packet(request.packet(), outgoing)?;
+            self.source = Some(Box::new(file));
+            self.transfer_type = Some(TransferType::Write);
+            self.awaiting_oack = awaiting_oack;
+            self.last_sent = outgoing[0..count].to_vec();
+            self.restart_timer(now);
+            Ok(count)
         } else {
             Err(TftprsError::BadRequestAttempted)
         }
@@ -113,29 +558,32 @@ impl<'a> Machine<'a> {
     /// Sends a request to the remote peer to receive / read a file from that peer.
     pub fn request_receive_file(
         &mut self,
+        now: Instant,
         filename: String,
-        file: &'a mut Vec<u8>,
-        outgoing: &mut [u8; MAX_PACKET_SIZE],
+        file: impl FileSink + Send + 'a,
+        outgoing: &mut [u8],
     ) -> Result<usize, TftprsError> {
         // Do not send a request if a transaction is already taking place.
         if self.is_busy() {
             return Err(TftprsError::Busy);
         }
-        // Do not allow files that are too large for the block field.
-        if file.len() > u16::MAX as usize * MAX_DATA_SIZE {
+        // Mail can only be sent.
+        if self.mode == Mode::Mail {
             return Err(TftprsError::BadRequestAttempted);
         }
+        self.reset();
         // Expect first block of data in response
         self.block = 1;
-        if let Ok(request) = Request::new(TransferType::Read, self.mode, filename) {
-            let count = request.serialize(outgoing);
-            if request.serialize(outgoing) > 0 {
-                self.incoming_file = Some(file);
-                self.transfer_type = Some(TransferType::Read);
-                Ok(count)
-            } else {
-                Err(TftprsError::BadRequestAttempted)
-            }
+        if let Ok(request) = Request::new(TransferType::Read, self.mode, filename)
+            .and_then(|request| request.with_options(self.request_options(Some(0))))
+        {
+            let count = Self::write_packet(request.packet(), outgoing)?;
+            self.sink = Some(Box::new(file));
+            self.transfer_type = Some(TransferType::Read);
+            self.awaiting_oack = !self.options.is_empty();
+            self.last_sent = outgoing[0..count].to_vec();
+            self.restart_timer(now);
+            Ok(count)
         } else {
             Err(TftprsError::BadRequestAttempted)
         }
@@ -143,280 +591,965 @@ impl<'a> Machine<'a> {
 
     /// Responds to a request from a remote peer to read / receive a file from the host. This is
     /// a write transfer from the host's perspective.
+    ///
+    /// If the host accepted any options, the reply is an option acknowledgement, and the first block is sent
+    /// once the peer acknowledges it with block 0.
+    ///
+    /// Mail cannot be read, so a read request in mail mode can only be refused with `reject_mail()`.
+    ///
+    /// If the peer asked for the transfer size and the length of the file is not known, the option is left out of
+    /// the option acknowledgement.
     pub fn reply_send_file(
         &mut self,
-        file: &'a Vec<u8>,
-        outgoing: &mut [u8; MAX_PACKET_SIZE],
+        now: Instant,
+        file: impl FileSource + Send + 'a,
+        outgoing: &mut [u8],
     ) -> Result<usize, TftprsError> {
         if !self.is_busy() {
             return Err(TftprsError::NoConnection);
         }
-        self.outgoing_file = Some(file);
+        if self.mode == Mode::Mail {
+            return Err(TftprsError::BadRequestAttempted);
+        }
+        // Declare the size of the file if the peer asked for it.
+        let length = file.len();
+        self.negotiated_options
+            .retain(|option| !option.is(TRANSFER_SIZE_OPTION) || length.is_some());
+        for option in &mut self.negotiated_options {
+            if let (true, Some(length)) = (option.is(TRANSFER_SIZE_OPTION), length) {
+                option.value = length.to_string();
+            }
+        }
+        self.source = Some(Box::new(file));
+        self.apply_options();
         self.block = 1;
-        self.send_block(outgoing)
+        let response = if self.negotiated_options.is_empty() {
+            self.send_window(outgoing)
+        } else {
+            // Expect an ack at block 0 for the option acknowledgement.
+            self.window_end = 0;
+            self.send_option_ack(outgoing)
+        };
+        self.restart_timer(now);
+        response
     }
 
     /// Responds to a request from a remote peer to write / send a file to the host. This is a
     /// read transfer from the host's perspective.
+    ///
+    /// If the host accepted any options, the reply is an option acknowledgement in place of the ack at block 0.
+    /// If the peer declared a transfer size larger than the host allows, the reply is instead a disk full error, and
+    /// the transfer fails with `TftprsError::LimitExceeded`.
     pub fn reply_receive_file(
         &mut self,
-        file: &'a mut Vec<u8>,
-        outgoing: &mut [u8; MAX_PACKET_SIZE],
+        now: Instant,
+        file: impl FileSink + Send + 'a,
+        outgoing: &mut [u8],
+    ) -> Result<usize, TftprsError> {
+        if !self.is_busy() {
+            return Err(TftprsError::NoConnection);
+        }
+        if self.exceeds_max_transfer_size() {
+            return self.refuse_large_file(outgoing);
+        }
+        self.sink = Some(Box::new(file));
+        self.accept_incoming(now, outgoing)
+    }
+
+    /// Responds to a request from a remote peer to send mail to the recipient in `recipient()`. The message is
+    /// received like a file in netascii mode, and once it is complete, the sink is called with the recipient and the
+    /// message before the final block is acknowledged.
+    pub fn reply_receive_mail(
+        &mut self,
+        now: Instant,
+        sink: impl FnMut(&str, &[u8]) + Send + 'a,
+        outgoing: &mut [u8],
     ) -> Result<usize, TftprsError> {
         if !self.is_busy() {
             return Err(TftprsError::NoConnection);
         }
-        self.incoming_file = Some(file);
-        // Acknowledge with a zero block, then advance the block.
-        let result = self.send_ack(outgoing);
+        let Some(recipient) = self.recipient.clone() else {
+            return Err(TftprsError::BadRequestAttempted);
+        };
+        if self.exceeds_max_transfer_size() {
+            return self.refuse_large_file(outgoing);
+        }
+        self.mail = Some(MailDelivery {
+            recipient,
+            message: Vec::new(),
+            sink: Box::new(sink),
+        });
+        self.accept_incoming(now, outgoing)
+    }
+
+    /// Refuses a request from a remote peer in mail mode with an illegal operation error, for hosts that do not take
+    /// mail. This operation automatically resets the machine.
+    pub fn reject_mail(&mut self, outgoing: &mut [u8]) -> Result<usize, TftprsError> {
+        if !self.is_busy() {
+            return Err(TftprsError::NoConnection);
+        }
+        self.send_error(
+            ErrorCode::IllegalOperation,
+            outgoing,
+            String::from("Mail is not accepted"),
+        )
+    }
+
+    /// Acknowledges a request to send data to the host, once the host provided somewhere to put it.
+    fn accept_incoming(&mut self, now: Instant, outgoing: &mut [u8]) -> Result<usize, TftprsError> {
+        self.apply_options();
+        // Acknowledge with a zero block, then expect the first block.
         self.block = 1;
-        result
+        let response = if self.negotiated_options.is_empty() {
+            self.send_ack(0, outgoing)
+        } else {
+            self.send_option_ack(outgoing)
+        };
+        self.restart_timer(now);
+        response
     }
 
     /// Listens for (i.e., parses an incoming spontaneous message) to check for a request from a remote peer.
     /// To determine the direction of the request, check `request_type()`. If the remote peer sent
     /// a `OpCode::WriteRequest` request, this will be referenced as a `TransferType::Read` in the host's machine.
     /// Likewise, if the peer sent an `OpCode::ReadRequest`, then the host considers it an active `TransferType::Write`.
+    ///
+    /// Any options in the request are available from `requested_options()` until the host replies, and those that the
+    /// option policy allows are accepted right away. A transfer size
+    /// declared by a peer that wants to send a file is available from `transfer_size()`.
+    ///
+    /// The received slice must hold exactly the bytes of the datagram. The host passes the address the request came
+    /// from. The transfer is bound to it, and every later message
+    /// must come from the same address.
     pub fn listen_for_request(
         &mut self,
-        received: &[u8; MAX_PACKET_SIZE],
+        from: SocketAddr,
+        received: &[u8],
     ) -> Result<String, TftprsError> {
         if self.is_busy() {
             return Err(TftprsError::Busy);
         }
-        if let Ok(opcode_bytes) = received[0..2].try_into() {
-            // Determine dispatch based on op code.
-            let opcode: u16 = u16::from_be_bytes(opcode_bytes);
-            if let Ok(opcode_match) = OpCode::try_from(opcode) {
-                match opcode_match {
-                    // Handle incoming write request (read).
-                    OpCode::WriteRequest => {
-                        let filename = self.parse_request(received)?;
-                        self.transfer_type = Some(TransferType::Read);
-                        Ok(filename)
-                    }
-                    // Handle incoming read request (write).
-                    OpCode::ReadRequest => {
-                        let filename = self.parse_request(received)?;
-                        self.transfer_type = Some(TransferType::Write);
-                        Ok(filename)
-                    }
-                    // This was an attempt to send us transfer messages when there is no connection,
-                    //  or it was an unexpected error packet
-                    _ => Err(TftprsError::NoConnection),
-                }
-            } else {
-                Err(TftprsError::BadPacketReceived)
+        let packet = Packet::parse_with(received, self.strictness)
+            .map_err(|_| TftprsError::BadPacketReceived)?;
+        self.reset();
+        let (transfer_type, filename, mode, options) = match packet {
+            // Handle incoming write request (read).
+            Packet::WriteRequest {
+                filename,
+                mode,
+                options,
+            } => (TransferType::Read, filename, mode, options),
+            // Handle incoming read request (write).
+            Packet::ReadRequest {
+                filename,
+                mode,
+                options,
+            } => (TransferType::Write, filename, mode, options),
+            // This was an attempt to send us transfer messages when there is no connection,
+            //  or it was an unexpected error packet
+            _ => return Err(TftprsError::NoConnection),
+        };
+        self.mode = Mode::parse(mode, self.strictness).ok_or(TftprsError::BadPacketReceived)?;
+        self.requested_options = options.to_vec();
+        self.negotiated_options = self
+            .requested_options
+            .iter()
+            .filter_map(|option| self.option_policy.reply(option))
+            .collect();
+        self.transfer_type = Some(transfer_type);
+        self.peer = Some(from);
+        if transfer_type == TransferType::Read {
+            if self.mode == Mode::Mail {
+                self.recipient = Some(filename.to_string());
             }
-        } else {
-            Err(TftprsError::BadPacketReceived)
+            self.transfer_size = find_option(&self.requested_options, TRANSFER_SIZE_OPTION)
+                .and_then(|option| parse_transfer_size(&option.value));
         }
+        Ok(filename.to_string())
     }
 
     /// Processes incoming messages while a transfer is active. It does not matter who initiated the transfer,
     /// whether it was the host or the remote peer.
+    ///
+    /// Stale data and acks, such as duplicates caused by retransmission, are not errors. They are ignored and
+    /// nothing is written, unless a duplicate block must be acknowledged again. An error from the peer ends the
+    /// transfer, and is returned as `Event::PeerError`. When the machine ends the transfer itself, such as on an
+    /// unacceptable option acknowledgement, it returns an error that carries the length of the error message it
+    /// wrote out for the peer, so that `Event::Transmit` always means the transfer goes on.
+    ///
+    /// The received slice must hold exactly the bytes of the datagram.
+    ///
+    /// The host passes the address each message came from. When the host made the request, the transfer is bound to
+    /// the address of the first reply. A message from any other address gets an unknown transfer ID error in return,
+    /// which is written out and reported with `TftprsError::UnknownTransferId`. The host must send it back to that
+    /// address, and the transfer with the real peer carries on.
     pub fn process(
         &mut self,
-        received: &[u8; MAX_PACKET_SIZE],
-        length: usize,
-        outgoing: &mut [u8; MAX_PACKET_SIZE],
-    ) -> Result<usize, TftprsError> {
+        now: Instant,
+        from: SocketAddr,
+        received: &[u8],
+        outgoing: &mut [u8],
+    ) -> Result<Event, TftprsError> {
         // Drop unexpected packets.
         if !self.is_busy() {
             return Err(TftprsError::NoConnection);
         }
         // Sanity check.
-        if length > MAX_PACKET_SIZE {
+        if !(2..=MAX_PACKET_SIZE).contains(&received.len()) {
+            if self.peer == Some(from) {
+                return self.reject_violation(
+                    outgoing,
+                    TftprsError::BadPacketReceived,
+                    "Malformed packet",
+                );
+            }
             return Err(TftprsError::BadPacketReceived);
         }
-        if let Ok(opcode_bytes) = received[0..2].try_into() {
-            // Determine dispatch based on op code.
-            let opcode: u16 = u16::from_be_bytes(opcode_bytes);
-            if let Ok(opcode_match) = OpCode::try_from(opcode) {
-                match opcode_match {
-                    // Handle ack if we are writing.
-                    OpCode::Acknowledgement => {
-                        if let Some(TransferType::Write) = self.transfer_type {
-                            self.handle_ack_and_send_next_block(received, outgoing)
-                        } else {
-                            Err(TftprsError::BadPacketReceived)
-                        }
-                    }
-                    // Handle data if we are reading.
-                    OpCode::Data => {
-                        if let Some(TransferType::Read) = self.transfer_type {
-                            self.handle_data_and_send_ack(
-                                received,
-                                length - FIXED_DATA_BYTES,
-                                outgoing,
-                            )
-                        } else {
-                            Err(TftprsError::BadPacketReceived)
-                        }
-                    }
-                    // Terminate on error.
-                    OpCode::Error => {
-                        self.reset();
-                        Err(Self::parse_error(received))
-                    }
-                    // This was an attempt to send us a request when we already busy.
-                    _ => Err(TftprsError::Busy),
+        // Turn away anyone other than the peer.
+        if self.peer.is_some_and(|peer| peer != from) {
+            return Self::reject_foreign_packet(received, outgoing);
+        }
+        let packet = match Packet::parse_with(received, self.strictness) {
+            Ok(packet) => packet,
+            // Only answer the peer, once it is known.
+            Err(_) if self.peer.is_none() => return Err(TftprsError::BadPacketReceived),
+            Err(_) => {
+                return self.reject_violation(
+                    outgoing,
+                    TftprsError::BadPacketReceived,
+                    "Malformed packet",
+                );
+            }
+        };
+        // Lock onto the peer's TID with its first reply.
+        self.peer = Some(from);
+        if self.dallying {
+            if self.dally_expired(now) {
+                self.finish(TransferState::Completed);
+                return Ok(Event::DallyEnded);
+            }
+            return self.handle_dally(packet, outgoing);
+        }
+        if self
+            .transfer_deadline()
+            .is_some_and(|deadline| now >= deadline)
+        {
+            return self.fail_on_limit(ErrorCode::Undefined, "Transfer took too long", outgoing);
+        }
+        match packet {
+            // Handle ack if we are writing.
+            Packet::Ack { block } => {
+                if let Some(TransferType::Write) = self.transfer_type {
+                    // The peer ignored our options, if any.
+                    self.awaiting_oack = false;
+                    self.handle_ack_and_send_next_block(now, block, outgoing)
+                } else {
+                    self.reject_violation(
+                        outgoing,
+                        TftprsError::BadPacketReceived,
+                        "Unexpected acknowledgement",
+                    )
                 }
+            }
+            // Handle data if we are reading.
+            Packet::Data { block, payload } => {
+                if payload.len() > self.block_size {
+                    self.reject_violation(
+                        outgoing,
+                        TftprsError::BadPacketReceived,
+                        "Block is larger than the block size",
+                    )
+                } else if let Some(TransferType::Read) = self.transfer_type {
+                    // The peer ignored our options, if any.
+                    self.awaiting_oack = false;
+                    self.handle_data_and_send_ack(now, block, payload, outgoing)
+                } else {
+                    self.reject_violation(
+                        outgoing,
+                        TftprsError::BadPacketReceived,
+                        "Unexpected data",
+                    )
+                }
+            }
+            // Handle the option acknowledgement of our request.
+            Packet::OptionAck { options } => self.handle_option_ack(now, options, outgoing),
+            // Terminate on error.
+            Packet::Error { code, message } => {
+                self.finish(TransferState::Failed);
+                Ok(Event::PeerError(code, message.to_string()))
+            }
+            // This was an attempt to send us a request when we already busy.
+            Packet::ReadRequest { .. } | Packet::WriteRequest { .. } => {
+                self.reject_violation(outgoing, TftprsError::Busy, "Unexpected request")
+            }
+        }
+    }
+
+    /// Writes out the next outgoing message, if any, that is due after the last reply. When writing with a window size
+    /// larger than 1, the reply to an ack carries only the first data packet of the window, and the host should call
+    /// this until it returns `None` to transmit the rest of the window.
+    pub fn poll_transmit(&mut self, outgoing: &mut [u8]) -> Result<Option<usize>, TftprsError> {
+        if self.transfer_type != Some(TransferType::Write)
+            || self.source.is_none()
+            || self.next_block == 0
+            || self.next_block > self.window_end
+            || self.last_block.is_some_and(|last| self.next_block > last)
+        {
+            return Ok(None);
+        }
+        let block = self.next_block;
+        let count = self.send_block(block, outgoing)?;
+        let payload_length = count - FIXED_DATA_BYTES;
+        if payload_length < self.block_size {
+            // This is the final block, so the window ends here.
+            self.last_block = Some(block);
+            self.window_end = block;
+            self.end_offset = if self.mode.is_netascii() {
+                self.encoders.back().map_or(0, Encoder::offset)
             } else {
-                Err(TftprsError::BadPacketReceived)
+                (block - 1) * self.block_size as u64 + payload_length as u64
+            };
+        }
+        self.next_block = block + 1;
+        Ok(Some(count))
+    }
+
+    /// Retransmits the last message if it went unanswered past the deadline given by `poll_timeout()`. When writing,
+    /// the whole window is sent again, and the host should drain it with `poll_transmit()`. When reading in the middle
+    /// of a window, the last block received in order is acknowledged. Nothing is written if the deadline has not yet
+    /// passed, and the deadline is returned in `Event::NeedTimer` instead.
+    ///
+    /// Once the retries are used up, the machine resets and the transfer fails with `TftprsError::Timeout`.
+    pub fn handle_timeout(
+        &mut self,
+        now: Instant,
+        outgoing: &mut [u8],
+    ) -> Result<Event, TftprsError> {
+        if !self.is_busy() {
+            return Ok(Event::Ignored(IgnoreReason::Idle));
+        }
+        if self.dallying {
+            if self.dally_expired(now) {
+                self.finish(TransferState::Completed);
+                return Ok(Event::DallyEnded);
             }
-        } else {
-            Err(TftprsError::BadPacketReceived)
+            return Ok(self.wait(now));
+        }
+        if self
+            .transfer_deadline()
+            .is_some_and(|deadline| now >= deadline)
+        {
+            return self.fail_on_limit(ErrorCode::Undefined, "Transfer took too long", outgoing);
         }
+        match self.timer.expire(now, self.timeout) {
+            Ok(true) => {}
+            Ok(false) => return Ok(self.wait(now)),
+            Err(e) => {
+                self.finish(TransferState::Failed);
+                return Err(e);
+            }
+        }
+        self.total_retries += 1;
+        if self
+            .limits
+            .max_retries
+            .is_some_and(|max| self.total_retries > max)
+        {
+            return self.fail_on_limit(ErrorCode::Undefined, "Too many retransmissions", outgoing);
+        }
+        if self.transfer_type == Some(TransferType::Read) && self.window_count > 0 {
+            // Acknowledge the part of the window that did arrive.
+            self.window_count = 0;
+            return self.send_ack(self.block - 1, outgoing).map(Event::Transmit);
+        }
+        if self.last_sent.is_empty() {
+            // Go back to the start of the window.
+            self.congestion.time_out();
+            return self.send_window(outgoing).map(Event::Transmit);
+        }
+        self.resend_last(outgoing).map(Event::Transmit)
     }
 
     /// Formulate an error and write it to the transmit buffer. The host can do this at any time.
-    /// This operation automatically resets the machine.
+    /// This operation automatically resets the machine, and an active transfer counts as failed, unless the file was
+    /// already received in full.
     pub fn send_error(
         &mut self,
         code: ErrorCode,
-        outgoing: &mut [u8; MAX_PACKET_SIZE],
+        outgoing: &mut [u8],
         message: String,
     ) -> Result<usize, TftprsError> {
         let error_message = ErrorResponse::new(code, message);
-        let count = error_message.serialize(outgoing);
+        let count = Self::write_packet(error_message.packet(), outgoing)?;
+        if self.dallying {
+            self.finish(TransferState::Completed);
+        } else if self.is_busy() {
+            self.finish(TransferState::Failed);
+        }
+        Ok(count)
+    }
+
+    /// Cancels the active transfer, and writes an error with the given reason to the transmit buffer. A partially
+    /// received file is discarded by its sink. Returns what the transfer had achieved.
+    pub fn cancel(
+        &mut self,
+        reason: String,
+        outgoing: &mut [u8],
+    ) -> Result<TransferSummary, TftprsError> {
+        let Some(transfer_type) = self.transfer_type else {
+            return Err(TftprsError::NoConnection);
+        };
+        let state = self.state();
+        let transmit = self.send_error(ErrorCode::Undefined, outgoing, reason)?;
+        Ok(TransferSummary {
+            transfer_type,
+            state,
+            bytes: self.bytes,
+            blocks: self.blocks,
+            transmit,
+        })
+    }
+
+    /// Ends the active transfer, and keeps its outcome and progress until the next one starts. When it failed, a
+    /// partially received file is discarded by its sink.
+    fn finish(&mut self, outcome: TransferState) {
+        if outcome == TransferState::Failed
+            && let Some(sink) = self.sink.as_deref_mut()
+        {
+            // The transfer already failed, so a sink that cannot discard the data is left as it is.
+            let _ = sink.abort(self.bytes);
+        }
+        let (bytes, blocks, congestion) = (self.bytes, self.blocks, self.congestion);
         self.reset();
+        self.outcome = Some(outcome);
+        self.bytes = bytes;
+        self.blocks = blocks;
+        self.congestion = congestion;
+    }
+
+    /// The interval after which an unanswered message is retransmitted. This is the negotiated timeout if there is
+    /// one, and otherwise the configured interval, or the estimate from the round trip time if the timeout adapts.
+    pub fn retransmit_timeout(&self) -> Duration {
+        self.timeout.unwrap_or_else(|| self.timer.rto())
+    }
+
+    /// The congestion window of the active transfer or the last one, if congestion control is enabled.
+    pub fn congestion_stats(&self) -> Option<CongestionStats> {
+        self.congestion_control.then(|| self.congestion.stats())
+    }
+
+    /// The round trip time measured from the replies of the peer. The measurements carry over from one transfer to
+    /// the next.
+    pub fn rtt_stats(&self) -> RttStats {
+        self.timer.rtt()
+    }
+
+    /// Tells the host to wait for the next message until the retransmission is due.
+    fn wait(&self, now: Instant) -> Event {
+        Event::NeedTimer(self.poll_timeout().unwrap_or(now))
+    }
+
+    /// Restarts the retransmission timer after the transfer made progress, unless the transfer already ended. The
+    /// round trip of the message that was answered is measured, and the reply that goes out is timed in turn.
+    fn restart_timer(&mut self, now: Instant) {
+        if self.is_busy() {
+            // The first message of a transfer starts the clock on its duration.
+            self.started.get_or_insert(now);
+            self.timer.sample(now);
+            self.timer.start(now, self.timeout);
+        }
+    }
+
+    /// Writes out the last message other than data again.
+    fn resend_last(&self, outgoing: &mut [u8]) -> Result<usize, TftprsError> {
+        let count = self.last_sent.len();
+        if outgoing.len() < count {
+            return Err(TftprsError::BufferTooSmall {
+                needed: count,
+                available: outgoing.len(),
+            });
+        }
+        outgoing[0..count].copy_from_slice(&self.last_sent);
         Ok(count)
     }
 
-    /// Helper to parse a variable length string in a message.
-    fn parse_string(
-        received: &[u8; MAX_PACKET_SIZE],
-        cursor: &mut usize,
-        cursor_limit: usize,
-    ) -> Result<String, TftprsError> {
-        let mut result = String::new();
-        while received[*cursor] != TERMINATOR_BYTE {
-            result.push(char::from(received[*cursor]));
-            *cursor += 1;
-            if *cursor >= cursor_limit {
-                return Err(TftprsError::BadPacketReceived);
+    /// Helper to write a packet to the transmit buffer.
+    fn write_packet(packet: Packet<'_>, outgoing: &mut [u8]) -> Result<usize, TftprsError> {
+        packet.serialize(outgoing).map_err(|e| match e {
+            PacketError::BufferTooSmall { needed, available } => {
+                TftprsError::BufferTooSmall { needed, available }
             }
+            _ => TftprsError::BadRequestAttempted,
+        })
+    }
+
+    /// Answers a message from an unknown sender with an error, without disturbing the active transfer. Error messages
+    /// are never answered.
+    fn reject_foreign_packet(received: &[u8], outgoing: &mut [u8]) -> Result<Event, TftprsError> {
+        if received[0..2] == (OpCode::Error as u16).to_be_bytes() {
+            return Ok(Event::Ignored(IgnoreReason::ForeignError));
         }
-        // Step past terminator byte
-        *cursor += 1;
-        Ok(result)
+        let error_message = ErrorResponse::new(
+            ErrorCode::UnknownTransferId,
+            String::from("Unknown transfer ID"),
+        );
+        Err(TftprsError::UnknownTransferId(Self::write_packet(
+            error_message.packet(),
+            outgoing,
+        )?))
     }
 
-    /// Helper to parse an incoming request from a peer.
-    fn parse_request(&mut self, received: &[u8; MAX_PACKET_SIZE]) -> Result<String, TftprsError> {
-        let mut cursor: usize = 2;
-        let filename = Self::parse_string(
-            received,
-            &mut cursor,
-            MAX_PACKET_SIZE - BINARY_MODE.len() - 2,
-        )?;
-        let mode = Self::parse_string(received, &mut cursor, MAX_PACKET_SIZE - 1)?;
-        if mode.eq(TEXT_MODE) {
-            self.mode = Mode::Text;
-        } else if mode.eq(BINARY_MODE) {
-            self.mode = Mode::Binary;
-        } else {
-            return Err(TftprsError::BadPacketReceived);
+    /// Refuses a message from the peer that breaks the protocol with the given error. If the host asked for it, an
+    /// illegal operation error is written out for the peer, and the transfer ends, since the peer stops once it gets
+    /// the error.
+    fn reject_violation(
+        &mut self,
+        outgoing: &mut [u8],
+        error: TftprsError,
+        message: &str,
+    ) -> Result<Event, TftprsError> {
+        if !self.reply_to_violations {
+            return Err(error);
         }
-        Ok(filename)
+        let count = self.send_error(ErrorCode::IllegalOperation, outgoing, message.to_string())?;
+        Err(TftprsError::IllegalOperation(count))
     }
 
-    /// Helper to parse an error message from a peer.
-    fn parse_error(received: &[u8; MAX_PACKET_SIZE]) -> TftprsError {
-        let mut cursor: usize = 2;
-        if let Ok(error_code_bytes) = received[cursor..cursor + 2].try_into() {
-            let error_code = u16::from_be_bytes(error_code_bytes);
-            cursor += 2;
-            if let Ok(message) = Self::parse_string(received, &mut cursor, MAX_PACKET_SIZE - 1) {
-                TftprsError::ErrorResponse(error_code, message)
-            } else {
-                TftprsError::BadPacketReceived
+    /// Writes out the option acknowledgement for the options the host accepted.
+    fn send_option_ack(&mut self, outgoing: &mut [u8]) -> Result<usize, TftprsError> {
+        let oack = OptionAck::new(self.negotiated_options.clone());
+        let count = Self::write_packet(oack.packet(), outgoing)?;
+        self.last_sent = outgoing[0..count].to_vec();
+        Ok(count)
+    }
+
+    /// Applies the options the peer acknowledged, and then continues the transfer as if the peer had sent the
+    /// ack at block 0 (for a write) or as if we were acknowledging block 0 (for a read).
+    ///
+    /// The peer sends the option acknowledgement again if our reply to it was lost, so until block 1 gets through,
+    /// another one is a duplicate. When reading, the ack at block 0 is sent again. When writing, the retransmission
+    /// of the window takes care of it.
+    fn handle_option_ack(
+        &mut self,
+        now: Instant,
+        options: Options<'_>,
+        outgoing: &mut [u8],
+    ) -> Result<Event, TftprsError> {
+        if self.oack_received && self.block == 1 {
+            return match self.transfer_type {
+                Some(TransferType::Read) => self.resend_last(outgoing).map(Event::Transmit),
+                _ => Ok(Event::Ignored(IgnoreReason::DuplicateOptionAck)),
+            };
+        }
+        if !self.awaiting_oack {
+            return self.reject_violation(
+                outgoing,
+                TftprsError::BadPacketReceived,
+                "Unexpected option acknowledgement",
+            );
+        }
+        self.awaiting_oack = false;
+        self.oack_received = true;
+        let options = options.to_vec();
+        // The peer may only acknowledge options that we proposed, and only with acceptable values.
+        for option in &options {
+            let acceptable = find_option(&self.options, &option.name)
+                .is_some_and(|proposed| option.answers(proposed));
+            if !acceptable {
+                let count = self.send_error(
+                    ErrorCode::OptionNegotiation,
+                    outgoing,
+                    format!("Unacceptable option {}", option.name),
+                )?;
+                return Err(TftprsError::OptionNegotiation(count));
             }
+        }
+        self.negotiated_options = options;
+        self.apply_options();
+        // Refuse a file that is too large before it is sent.
+        if self.transfer_type == Some(TransferType::Read) && self.exceeds_max_transfer_size() {
+            return self.refuse_large_file(outgoing);
+        }
+        self.restart_timer(now);
+        match self.transfer_type {
+            Some(TransferType::Write) => self.send_window(outgoing).map(Event::Transmit),
+            Some(TransferType::Read) => self.send_ack(0, outgoing).map(Event::Transmit),
+            None => Err(TftprsError::NoConnection),
+        }
+    }
+
+    /// The options to send in a request, with the transfer size filled in. The transfer size is left out if it is
+    /// not known.
+    fn request_options(&self, transfer_size: Option<u64>) -> Vec<TransferOption> {
+        self.options
+            .iter()
+            .filter_map(|option| {
+                if option.is(TRANSFER_SIZE_OPTION) {
+                    transfer_size
+                        .map(|size| TransferOption::new(option.name.clone(), size.to_string()))
+                } else {
+                    Some(option.clone())
+                }
+            })
+            .collect()
+    }
+
+    /// Configures the active transfer according to the negotiated options.
+    fn apply_options(&mut self) {
+        self.block_size = find_option(&self.negotiated_options, BLOCK_SIZE_OPTION)
+            .and_then(|option| parse_block_size(&option.value))
+            .unwrap_or(DEFAULT_BLOCK_SIZE);
+        self.timeout = find_option(&self.negotiated_options, TIMEOUT_OPTION)
+            .and_then(|option| parse_timeout(&option.value));
+        self.window_size = find_option(&self.negotiated_options, WINDOW_SIZE_OPTION)
+            .and_then(|option| parse_window_size(&option.value))
+            .unwrap_or(1);
+        self.negotiated_rollover = find_option(&self.negotiated_options, ROLLOVER_OPTION)
+            .and_then(|option| parse_rollover(&option.value));
+        if let Some(size) = find_option(&self.negotiated_options, TRANSFER_SIZE_OPTION)
+            .and_then(|option| parse_transfer_size(&option.value))
+        {
+            self.transfer_size = Some(size);
+        }
+    }
+
+    /// Indicates whether the declared transfer size is larger than the host is willing to receive.
+    fn exceeds_max_transfer_size(&self) -> bool {
+        match (self.transfer_size, self.limits.max_bytes) {
+            (Some(size), Some(max)) => size > max,
+            _ => false,
+        }
+    }
+
+    /// Refuses a file that is too large.
+    fn refuse_large_file<T>(&mut self, outgoing: &mut [u8]) -> Result<T, TftprsError> {
+        self.fail_on_limit(
+            ErrorCode::DiskFull,
+            "File exceeds the maximum transfer size",
+            outgoing,
+        )
+    }
+
+    /// When the active transfer runs out of time, if its duration is limited.
+    fn transfer_deadline(&self) -> Option<Instant> {
+        Some(self.started? + self.limits.max_duration?)
+    }
+
+    /// Ends the transfer after it hit a limit, and writes out the error for the peer.
+    fn fail_on_limit<T>(
+        &mut self,
+        code: ErrorCode,
+        message: &str,
+        outgoing: &mut [u8],
+    ) -> Result<T, TftprsError> {
+        let count = self.send_error(code, outgoing, String::from(message))?;
+        Err(TftprsError::LimitExceeded(count))
+    }
+
+    /// Writes out a block of the file. The length of the block is not known until it is read, so there must be room
+    /// for a full block. The file ends with the first block that comes up short.
+    fn send_block(&mut self, block: u64, outgoing: &mut [u8]) -> Result<usize, TftprsError> {
+        let needed = FIXED_DATA_BYTES + self.block_size;
+        if outgoing.len() < needed {
+            return Err(TftprsError::BufferTooSmall {
+                needed,
+                available: outgoing.len(),
+            });
+        }
+        let header = Self::write_packet(
+            Data::new(self.rollover().wire_block(block), &[]).packet(),
+            outgoing,
+        )?;
+        let payload = &mut outgoing[header..needed];
+        let result = if self.mode.is_netascii() {
+            self.read_encoded_block(block, payload)
         } else {
-            TftprsError::BadPacketReceived
+            let offset = (block - 1) * self.block_size as u64;
+            let source = self.source.as_deref_mut().ok_or(TftprsError::NoFile)?;
+            source.read_at(offset, payload)
+        };
+        match result {
+            Ok(count) => Ok(header + count),
+            Err(e) => Err(self.fail_on_file_error(e)),
         }
     }
 
-    /// Verifies that the block specified in the incoming message is as expected.
-    fn check_block_on_message(&self, received: &[u8; MAX_PACKET_SIZE]) -> Result<(), TftprsError> {
-        if let Ok(block_bytes) = received[2..4].try_into() {
-            let block = u16::from_be_bytes(block_bytes);
-            if block != self.block {
-                return Err(TftprsError::BadPacketReceived);
-            }
+    /// Reads a block of the file translated to netascii. Blocks are encoded in order from the window base, so the
+    /// position where the following block starts is saved each time a new block is encoded.
+    fn read_encoded_block(&mut self, block: u64, payload: &mut [u8]) -> io::Result<usize> {
+        let index = (block - self.block) as usize;
+        let (Some(mut encoder), Some(source)) = (
+            self.encoders.get(index).copied(),
+            self.source.as_deref_mut(),
+        ) else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Block is not in the window",
+            ));
+        };
+        let count = encoder.encode(source, payload, &mut self.netascii_buffer)?;
+        if index + 1 == self.encoders.len() {
+            self.encoders.push_back(encoder);
         }
-        Ok(())
+        Ok(count)
     }
 
-    /// Writes out the current block of the file.
-    fn send_block(&mut self, outgoing: &mut [u8; MAX_PACKET_SIZE]) -> Result<usize, TftprsError> {
-        if let Some(file) = &self.outgoing_file {
-            if let Some(data) = Data::new(self.block, file) {
-                let count = data.serialize(outgoing);
-                Ok(count)
-            } else {
-                self.reset();
-                Ok(0)
-            }
+    /// Abandons the transfer after the file source or sink failed.
+    fn fail_on_file_error(&mut self, error: io::Error) -> TftprsError {
+        self.finish(TransferState::Failed);
+        TftprsError::Io(error.to_string())
+    }
+
+    /// The number of blocks to send before waiting for an ack, which is the negotiated window size unless the
+    /// congestion window is smaller.
+    fn effective_window(&self) -> u16 {
+        if self.congestion_control {
+            self.congestion.window(self.window_size)
         } else {
-            Err(TftprsError::NoFile)
+            self.window_size
+        }
+    }
+
+    /// Starts a new window at the current block, and writes out its first block. The window ends early at the final
+    /// block of the file, if its length is known.
+    fn send_window(&mut self, outgoing: &mut [u8]) -> Result<usize, TftprsError> {
+        self.last_sent.clear();
+        self.next_block = self.block;
+        self.window_end = self.block + u64::from(self.effective_window()) - 1;
+        if let Some(last) = self.final_block() {
+            self.window_end = self.window_end.min(last.max(self.block));
         }
+        self.poll_transmit(outgoing)?.ok_or(TftprsError::NoFile)
     }
 
-    /// Checks the last ack, and then sends the next block.
+    /// The final (short) block of the file to send, if the file declares its length. In netascii mode, the length of
+    /// the encoded file is not known until the end is read.
+    fn final_block(&self) -> Option<u64> {
+        if self.mode.is_netascii() {
+            return None;
+        }
+        let length = self.source.as_deref()?.len()?;
+        Some(length / self.block_size as u64 + 1)
+    }
+
+    /// Checks the last ack, and then sends the next window of blocks.
+    ///
+    /// An ack for any block in the current window is valid. If it is not the last block sent, the rest of the window
+    /// was lost, and the next window starts again right after the acknowledged block (go-back-N).
+    ///
+    /// Any other ack is stale, most likely a duplicate of the ack that started the current window, and is ignored.
+    /// Answering duplicate acks with data would double the traffic for the rest of the transfer (the Sorcerer's
+    /// Apprentice Syndrome, RFC 1123 section 4.2.3.1).
     fn handle_ack_and_send_next_block(
         &mut self,
-        received: &[u8; MAX_PACKET_SIZE],
-        outgoing: &mut [u8; MAX_PACKET_SIZE],
-    ) -> Result<usize, TftprsError> {
+        now: Instant,
+        wire_block: u16,
+        outgoing: &mut [u8],
+    ) -> Result<Event, TftprsError> {
         // Verify the header.
-        self.check_block_on_message(received)?;
-        if self.block == u16::MAX {
-            // For safety, automatically terminate.
-            self.reset();
-            Ok(0)
+        let block = self
+            .rollover()
+            .logical_block_from(wire_block, self.block - 1);
+        let window_sent = self.window_end >= self.block;
+        if block > self.window_end {
+            // An ack from before the window looks like one far ahead of it, since block numbers wrap around.
+            let reason = if self.rollover().logical_block(wire_block, self.block) < self.block {
+                IgnoreReason::DuplicateAck
+            } else {
+                IgnoreReason::UnexpectedBlock
+            };
+            return Ok(Event::Ignored(reason));
+        }
+        if window_sent && block < self.block {
+            self.congestion.shrink();
+            return Ok(Event::Ignored(IgnoreReason::DuplicateAck));
+        }
+        if self.last_block == Some(block) {
+            // The final block was acknowledged.
+            self.bytes = self.end_offset;
+            self.blocks = block;
+            self.finish(TransferState::Completed);
+            Ok(Event::TransferComplete {
+                bytes: self.bytes,
+                blocks: self.blocks,
+                transmit: None,
+            })
         } else {
-            // Advance the block for the next write.
-            self.block += 1;
-            self.send_block(outgoing)
+            // Advance the window past the acknowledged block.
+            let offset = if self.mode.is_netascii() {
+                // Keep the position of the new window base.
+                let advance = (block + 1 - self.block) as usize;
+                if advance >= self.encoders.len() {
+                    // The block was never sent.
+                    return Ok(Event::Ignored(IgnoreReason::UnexpectedBlock));
+                }
+                self.encoders.drain(..advance);
+                self.encoders.front().map_or(0, Encoder::offset)
+            } else {
+                block * self.block_size as u64
+            };
+            // Everything before the new window base will not be sent again.
+            if let Some(source) = self.source.as_deref_mut() {
+                source.release(offset);
+            }
+            // An ack that answers the request or the option acknowledgement says nothing about the window.
+            if window_sent {
+                if block == self.window_end {
+                    self.congestion.grow(self.window_size);
+                } else {
+                    // The rest of the window was lost.
+                    self.congestion.shrink();
+                }
+            }
+            self.block = block + 1;
+            self.last_block = None;
+            self.bytes = offset;
+            self.blocks = block;
+            self.restart_timer(now);
+            self.send_window(outgoing).map(Event::Transmit)
         }
     }
 
     /// Send an ack.
-    fn send_ack(&mut self, outgoing: &mut [u8; MAX_PACKET_SIZE]) -> Result<usize, TftprsError> {
-        let ack = Ack::new(self.block);
-        let count = ack.serialize(outgoing);
+    fn send_ack(&mut self, block: u64, outgoing: &mut [u8]) -> Result<usize, TftprsError> {
+        let ack = Ack::new(self.rollover().wire_block(block));
+        let count = Self::write_packet(ack.packet(), outgoing)?;
+        self.last_sent = outgoing[0..count].to_vec();
         Ok(count)
     }
 
-    /// Receives the last datagram, and then sends an ack.
+    /// Keeps the final ack around after the file was received in full. The file is complete, so it is let go of.
+    fn dally(&mut self, now: Instant) {
+        self.dallying = true;
+        self.sink = None;
+        self.mail = None;
+        self.timer.start(now, Some(self.dally_duration));
+    }
+
+    /// Indicates whether the dally period is over.
+    fn dally_expired(&self, now: Instant) -> bool {
+        self.timer
+            .deadline()
+            .is_some_and(|deadline| now >= deadline)
+    }
+
+    /// Acknowledges the final block again while dallying, in case the peer did not get the final ack. Anything else
+    /// from the peer is ignored until the dally period ends.
+    fn handle_dally(
+        &mut self,
+        packet: Packet<'_>,
+        outgoing: &mut [u8],
+    ) -> Result<Event, TftprsError> {
+        match packet {
+            Packet::Data { block, .. }
+                if self.rollover().logical_block(block, self.block) == self.block =>
+            {
+                self.send_ack(self.block, outgoing).map(Event::Transmit)
+            }
+            _ => Ok(Event::Ignored(IgnoreReason::Dallying)),
+        }
+    }
+
+    /// Receives the last datagram, and then sends an ack if a window is complete.
+    ///
+    /// If a block arrives ahead of the expected one, the blocks in between were lost, so the last block received
+    /// in order is acknowledged once to have the sender start again from there.
+    ///
+    /// A block that was already received is not written again. If it is the one that was last acknowledged, the
+    /// sender likely missed the ack, so it is acknowledged again. Any other stale block is ignored.
     fn handle_data_and_send_ack(
         &mut self,
-        received: &[u8; MAX_PACKET_SIZE],
-        length: usize,
-        outgoing: &mut [u8; MAX_PACKET_SIZE],
-    ) -> Result<usize, TftprsError> {
+        now: Instant,
+        wire_block: u16,
+        payload: &[u8],
+        outgoing: &mut [u8],
+    ) -> Result<Event, TftprsError> {
         // Verify the header.
-        self.check_block_on_message(received)?;
-        if let Some(file) = &mut self.incoming_file {
-            // Write the received data.
-            for i in 0..length {
-                let idx = FIXED_DATA_BYTES + i;
-                file.push(received[idx]);
+        let block = self.rollover().logical_block(wire_block, self.block);
+        if block > self.block {
+            self.window_count = 0;
+            if self.gap_acked {
+                return Ok(Event::Ignored(IgnoreReason::OutOfOrder));
+            }
+            self.gap_acked = true;
+            return self.send_ack(self.block - 1, outgoing).map(Event::Transmit);
+        }
+        if block < self.block {
+            if block + 1 == self.block && block > 0 && self.window_count == 0 {
+                return self.send_ack(block, outgoing).map(Event::Transmit);
+            }
+            return Ok(Event::Ignored(IgnoreReason::DuplicateData));
+        }
+        let last = payload.len() < self.block_size;
+        // Translate the received data.
+        let data = if self.mode.is_netascii() {
+            self.netascii_buffer.clear();
+            self.decoder.decode(payload, &mut self.netascii_buffer);
+            if last {
+                self.decoder.finish(&mut self.netascii_buffer);
+            }
+            &self.netascii_buffer[..]
+        } else {
+            payload
+        };
+        // Stop a peer that sends more than the host is willing to receive.
+        if self
+            .limits
+            .max_bytes
+            .is_some_and(|max| self.bytes + data.len() as u64 > max)
+        {
+            return self.refuse_large_file(outgoing);
+        }
+        // Write the received data.
+        if let Some(mail) = &mut self.mail {
+            mail.message.extend_from_slice(data);
+        } else if let Some(sink) = self.sink.as_deref_mut() {
+            if let Err(e) = sink.write(data) {
+                return Err(self.fail_on_file_error(e));
+            }
+            // Count the data as soon as it is in the sink, so that a failure to finish discards all of it.
+            self.bytes += data.len() as u64;
+            if last && let Err(e) = sink.finish() {
+                return Err(self.fail_on_file_error(e));
             }
         } else {
             return Err(TftprsError::NoFile);
         }
-        // Acknowledge the received data.
-        let response = self.send_ack(outgoing);
-        if length < MAX_DATA_SIZE || self.block == u16::MAX {
-            // If there is no more data coming, then terminate.
-            self.reset();
+        if self.mail.is_some() {
+            self.bytes += data.len() as u64;
+        }
+        self.gap_acked = false;
+        self.window_count += 1;
+        self.blocks = block;
+        if last {
+            // If there is no more data coming, then deliver any mail, acknowledge, and terminate.
+            if let Some(mail) = &mut self.mail {
+                (mail.sink)(&mail.recipient, &mail.message);
+            }
+            let count = self.send_ack(block, outgoing)?;
+            if self.dally_duration.is_zero() {
+                self.finish(TransferState::Completed);
+            } else {
+                self.dally(now);
+            }
+            Ok(Event::TransferComplete {
+                bytes: self.bytes,
+                blocks: self.blocks,
+                transmit: Some(count),
+            })
         } else {
-            // Otherwise, advance the block.
+            // Otherwise, advance the block, and acknowledge the end of the window.
             self.block += 1;
+            if self.window_count == self.window_size {
+                self.window_count = 0;
+                self.restart_timer(now);
+                self.send_ack(block, outgoing).map(Event::Transmit)
+            } else {
+                // Wait for the rest of the window, without timing the wait as a round trip.
+                self.timer.sample(now);
+                self.timer.extend(now, self.timeout);
+                Ok(self.wait(now))
+            }
         }
-        response
     }
 }
@@ -0,0 +1,154 @@
+//! Netascii translation per RFC 1350 and RFC 764
+//!
+//! On the wire, every line ends with CR LF, and a carriage return that is not part of a line ending is sent as CR NUL.
+//! The host's files use a bare LF to end a line. Encoding grows the data, and either form may be split across two
+//! blocks, so both directions carry state from one block to the next.
+
+use std::io;
+
+use crate::file::FileSource;
+
+const CR: u8 = b'\r';
+const LF: u8 = b'\n';
+const NUL: u8 = 0x0;
+
+/// The position in a file that is being encoded, which is where the next block starts.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub(crate) struct Encoder {
+    // The offset of the next byte of the file to encode.
+    offset: u64,
+    // The second byte of a translation that did not fit in the last block.
+    pending: Option<u8>,
+}
+
+impl Encoder {
+    /// Encodes the file from the current position until the block is full or the file runs out, and then returns the
+    /// number of bytes written to the block. The file is read through `raw`, which is reused from block to block.
+    pub(crate) fn encode(
+        &mut self,
+        file: &mut dyn FileSource,
+        block: &mut [u8],
+        raw: &mut Vec<u8>,
+    ) -> io::Result<usize> {
+        // Each byte of the file takes at least one byte of the block, so this is all that can be needed.
+        raw.clear();
+        raw.resize(block.len(), 0);
+        let raw_count = file.read_at(self.offset, raw)?;
+        let mut raw_bytes = raw[0..raw_count].iter();
+        let mut count = 0;
+        while count < block.len() {
+            let byte = if let Some(byte) = self.pending.take() {
+                byte
+            } else if let Some(&byte) = raw_bytes.next() {
+                self.offset += 1;
+                match byte {
+                    LF => {
+                        self.pending = Some(LF);
+                        CR
+                    }
+                    CR => {
+                        self.pending = Some(NUL);
+                        CR
+                    }
+                    _ => byte,
+                }
+            } else {
+                break;
+            };
+            block[count] = byte;
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    /// The offset in the file where the next block starts.
+    pub(crate) fn offset(&self) -> u64 {
+        self.offset
+    }
+}
+
+/// Translates netascii data back to the host's format as the blocks arrive.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub(crate) struct Decoder {
+    // The last block ended with a CR, so its meaning depends on the first byte of the next block.
+    pending_cr: bool,
+}
+
+impl Decoder {
+    /// Decodes a block and appends it to the file.
+    pub(crate) fn decode(&mut self, block: &[u8], file: &mut Vec<u8>) {
+        for &byte in block {
+            if self.pending_cr {
+                self.pending_cr = false;
+                match byte {
+                    LF => file.push(LF),
+                    NUL => file.push(CR),
+                    CR => {
+                        // A malformed bare CR. Keep it, and look at what follows the next one.
+                        file.push(CR);
+                        self.pending_cr = true;
+                    }
+                    _ => file.extend_from_slice(&[CR, byte]),
+                }
+            } else if byte == CR {
+                self.pending_cr = true;
+            } else {
+                file.push(byte);
+            }
+        }
+    }
+
+    /// Ends the file. A CR at the very end has nothing to pair with, so it is kept as is.
+    pub(crate) fn finish(&mut self, file: &mut Vec<u8>) {
+        if self.pending_cr {
+            self.pending_cr = false;
+            file.push(CR);
+        }
+    }
+}
+
+mod test {
+    #[cfg(test)]
+    use super::*;
+
+    #[test]
+    fn test_encode() {
+        let mut encoder = Encoder::default();
+        let mut block = [0u8; 16];
+        let count = encoder
+            .encode(&mut &b"a\nb\rc\r\n"[..], &mut block, &mut Vec::new())
+            .unwrap();
+        assert_eq!(&block[0..count], b"a\r\nb\r\0c\r\0\r\n");
+    }
+
+    #[test]
+    fn test_encode_across_blocks() {
+        let file: &mut &[u8] = &mut &b"ab\ncd\r"[..];
+        let mut encoder = Encoder::default();
+        let mut block = [0u8; 3];
+        let mut raw = Vec::new();
+        assert_eq!(encoder.encode(file, &mut block, &mut raw).unwrap(), 3);
+        assert_eq!(&block, b"ab\r");
+        // Restarting from a saved position gives the same block again.
+        let saved = encoder;
+        assert_eq!(encoder.encode(file, &mut block, &mut raw).unwrap(), 3);
+        assert_eq!(&block, b"\ncd");
+        let mut encoder = saved;
+        assert_eq!(encoder.encode(file, &mut block, &mut raw).unwrap(), 3);
+        assert_eq!(&block, b"\ncd");
+        assert_eq!(encoder.encode(file, &mut block, &mut raw).unwrap(), 2);
+        assert_eq!(&block[0..2], b"\r\0");
+        assert_eq!(encoder.encode(file, &mut block, &mut raw).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_decode_across_blocks() {
+        let mut decoder = Decoder::default();
+        let mut file = Vec::new();
+        decoder.decode(b"ab\r", &mut file);
+        decoder.decode(b"\ncd\r", &mut file);
+        decoder.decode(b"\0e\r", &mut file);
+        decoder.finish(&mut file);
+        assert_eq!(file, b"ab\ncd\re\r");
+    }
+}
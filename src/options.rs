@@ -0,0 +1,160 @@
+//! Option extension per RFC 2347
+//!
+//! Options are appended to a request as pairs of null-terminated strings. The listening side
+//! answers with an option acknowledgement (OACK) that lists only the options it accepted.
+
+use std::time::Duration;
+
+use crate::constants::{MAX_BLOCK_SIZE, MIN_BLOCK_SIZE, Rollover};
+
+/// The name of the block size option (RFC 2348).
+pub const BLOCK_SIZE_OPTION: &str = "blksize";
+/// The name of the timeout interval option (RFC 2349).
+pub const TIMEOUT_OPTION: &str = "timeout";
+/// The name of the transfer size option (RFC 2349).
+pub const TRANSFER_SIZE_OPTION: &str = "tsize";
+/// The name of the window size option (RFC 7440).
+pub const WINDOW_SIZE_OPTION: &str = "windowsize";
+/// The name of the block number rollover option. It is not standardized, but common among servers
+/// that allow transfers of more than 65535 blocks.
+pub const ROLLOVER_OPTION: &str = "rollover";
+
+/// A single option, as carried in a request or an option acknowledgement.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize))]
+pub struct TransferOption {
+    /// The option name. Names are compared without regard to case.
+    pub name: String,
+    /// The option value.
+    pub value: String,
+}
+
+impl TransferOption {
+    pub fn new(name: impl Into<String>, value: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            value: value.into(),
+        }
+    }
+
+    /// Proposes a block size, in bytes, for the data in each packet.
+    pub fn block_size(size: u16) -> Self {
+        Self::new(BLOCK_SIZE_OPTION, size.to_string())
+    }
+
+    /// Proposes the number of seconds the sender waits before retransmitting.
+    pub fn timeout(seconds: u8) -> Self {
+        Self::new(TIMEOUT_OPTION, seconds.to_string())
+    }
+
+    /// Declares the size of the file. In a read request the size is not yet known and should be 0,
+    /// and the peer fills it in with its option acknowledgement.
+    pub fn transfer_size(size: u64) -> Self {
+        Self::new(TRANSFER_SIZE_OPTION, size.to_string())
+    }
+
+    /// Proposes the number of consecutive data packets the sender may transmit before it waits for an ack.
+    pub fn window_size(size: u16) -> Self {
+        Self::new(WINDOW_SIZE_OPTION, size.to_string())
+    }
+
+    /// Proposes the block number that follows block 65535.
+    pub fn rollover(rollover: Rollover) -> Self {
+        let value = match rollover {
+            Rollover::ToZero => "0",
+            Rollover::ToOne => "1",
+        };
+        Self::new(ROLLOVER_OPTION, value)
+    }
+
+    /// Indicates whether this option has the given name, ignoring case.
+    pub fn is(&self, name: &str) -> bool {
+        self.name.eq_ignore_ascii_case(name)
+    }
+
+    /// The number of bytes this option occupies on the wire, including both terminators.
+    pub(crate) fn wire_size(&self) -> usize {
+        self.name.len() + self.value.len() + 2
+    }
+
+    /// Indicates whether the value is well formed for the options this library interprets.
+    /// Any other option is passed through as is.
+    pub(crate) fn is_valid(&self) -> bool {
+        if self.is(BLOCK_SIZE_OPTION) {
+            parse_block_size(&self.value).is_some()
+        } else if self.is(TIMEOUT_OPTION) {
+            parse_timeout(&self.value).is_some()
+        } else if self.is(TRANSFER_SIZE_OPTION) {
+            parse_transfer_size(&self.value).is_some()
+        } else if self.is(WINDOW_SIZE_OPTION) {
+            parse_window_size(&self.value).is_some()
+        } else if self.is(ROLLOVER_OPTION) {
+            parse_rollover(&self.value).is_some()
+        } else {
+            true
+        }
+    }
+
+    /// Indicates whether this option is an acceptable reply to the proposed one. The block size and window size may
+    /// only be lowered and the timeout must be echoed, while the transfer size is whatever the sender declares.
+    pub(crate) fn answers(&self, proposed: &TransferOption) -> bool {
+        if !self.is(&proposed.name) || !self.is_valid() {
+            return false;
+        }
+        if self.is(BLOCK_SIZE_OPTION) {
+            parse_block_size(&proposed.value)
+                .is_some_and(|proposed| parse_block_size(&self.value) <= Some(proposed))
+        } else if self.is(TIMEOUT_OPTION) {
+            parse_timeout(&proposed.value) == parse_timeout(&self.value)
+        } else if self.is(WINDOW_SIZE_OPTION) {
+            parse_window_size(&proposed.value)
+                .is_some_and(|proposed| parse_window_size(&self.value) <= Some(proposed))
+        } else {
+            true
+        }
+    }
+}
+
+/// Finds an option by name, ignoring case.
+pub(crate) fn find_option<'a>(
+    options: &'a [TransferOption],
+    name: &str,
+) -> Option<&'a TransferOption> {
+    options.iter().find(|option| option.is(name))
+}
+
+/// Parses the value of a block size option, which must be within the range allowed by RFC 2348.
+pub(crate) fn parse_block_size(value: &str) -> Option<usize> {
+    value
+        .parse::<usize>()
+        .ok()
+        .filter(|size| (MIN_BLOCK_SIZE..=MAX_BLOCK_SIZE).contains(size))
+}
+
+/// Parses the value of a timeout option, which must be between 1 and 255 seconds per RFC 2349.
+pub(crate) fn parse_timeout(value: &str) -> Option<Duration> {
+    value
+        .parse::<u8>()
+        .ok()
+        .filter(|seconds| *seconds > 0)
+        .map(|seconds| Duration::from_secs(seconds.into()))
+}
+
+/// Parses the value of a transfer size option.
+pub(crate) fn parse_transfer_size(value: &str) -> Option<u64> {
+    value.parse::<u64>().ok()
+}
+
+/// Parses the value of a window size option, which must be between 1 and 65535 blocks per RFC 7440.
+pub(crate) fn parse_window_size(value: &str) -> Option<u16> {
+    value.parse::<u16>().ok().filter(|size| *size > 0)
+}
+
+/// Parses the value of a rollover option, which is the block number that follows block 65535.
+pub(crate) fn parse_rollover(value: &str) -> Option<Rollover> {
+    match value {
+        "0" => Some(Rollover::ToZero),
+        "1" => Some(Rollover::ToOne),
+        _ => None,
+    }
+}
@@ -0,0 +1,607 @@
+//! Packets on the wire
+//!
+//! A `Packet` is parsed from a datagram without copying, so its strings and data borrow from the receive buffer.
+//! It can also be built by hand and serialized into any buffer, which is useful for tools, proxies and tests.
+//! Neither direction panics on malformed input or short buffers. Each problem is reported as a `PacketError`.
+
+use std::fmt;
+use std::slice;
+use std::str;
+
+use crate::constants::{OpCode, Strictness};
+use crate::errors::PacketError;
+use crate::options::TransferOption;
+
+const TERMINATOR_BYTE: u8 = 0x0;
+
+/// A TFTP packet of any kind.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Packet<'a> {
+    /// A request to read a file (RRQ).
+    ReadRequest {
+        filename: &'a str,
+        /// The mode as it appears on the wire, such as "octet" or "netascii".
+        mode: &'a str,
+        options: Options<'a>,
+    },
+    /// A request to write a file (WRQ).
+    WriteRequest {
+        filename: &'a str,
+        /// The mode as it appears on the wire, such as "octet" or "netascii".
+        mode: &'a str,
+        options: Options<'a>,
+    },
+    /// A block of a file (DATA).
+    Data { block: u16, payload: &'a [u8] },
+    /// An acknowledgement of a block (ACK).
+    Ack { block: u16 },
+    /// An error (ERROR). The code is kept as a number, since peers may send codes that are not defined.
+    Error { code: u16, message: &'a str },
+    /// An option acknowledgement (OACK), per RFC 2347.
+    OptionAck { options: Options<'a> },
+}
+
+impl<'a> Packet<'a> {
+    /// Parses a datagram. The slice must hold exactly the bytes received, and nothing may follow the end of the
+    /// packet.
+    pub fn parse(datagram: &'a [u8]) -> Result<Self, PacketError> {
+        Self::parse_with(datagram, Strictness::Strict)
+    }
+
+    /// Parses a datagram, as closely to the RFC as the given strictness asks for.
+    ///
+    /// Some clients pad their packets with zeros. When lenient, any zeros after the last string of a request, an
+    /// error, or an option acknowledgement are ignored, as are any zeros after an ack.
+    pub fn parse_with(datagram: &'a [u8], strictness: Strictness) -> Result<Self, PacketError> {
+        let padded = strictness == Strictness::Lenient;
+        let (opcode, rest) = split_u16(datagram)?;
+        match OpCode::try_from(opcode).map_err(|_| PacketError::UnknownOpCode(opcode))? {
+            OpCode::ReadRequest => {
+                let (filename, mode, options) = parse_request(rest, padded)?;
+                Ok(Packet::ReadRequest {
+                    filename,
+                    mode,
+                    options,
+                })
+            }
+            OpCode::WriteRequest => {
+                let (filename, mode, options) = parse_request(rest, padded)?;
+                Ok(Packet::WriteRequest {
+                    filename,
+                    mode,
+                    options,
+                })
+            }
+            OpCode::Data => {
+                let (block, payload) = split_u16(rest)?;
+                Ok(Packet::Data { block, payload })
+            }
+            OpCode::Acknowledgement => {
+                let (block, rest) = split_u16(rest)?;
+                expect_end(rest, padded)?;
+                Ok(Packet::Ack { block })
+            }
+            OpCode::Error => {
+                let (code, rest) = split_u16(rest)?;
+                let (message, rest) = split_string(rest)?;
+                expect_end(rest, padded)?;
+                Ok(Packet::Error { code, message })
+            }
+            OpCode::OptionAcknowledgement => Ok(Packet::OptionAck {
+                options: Options::parse(rest, padded)?,
+            }),
+        }
+    }
+
+    /// The operation code of the packet.
+    pub fn opcode(&self) -> OpCode {
+        match self {
+            Packet::ReadRequest { .. } => OpCode::ReadRequest,
+            Packet::WriteRequest { .. } => OpCode::WriteRequest,
+            Packet::Data { .. } => OpCode::Data,
+            Packet::Ack { .. } => OpCode::Acknowledgement,
+            Packet::Error { .. } => OpCode::Error,
+            Packet::OptionAck { .. } => OpCode::OptionAcknowledgement,
+        }
+    }
+
+    /// The number of bytes the packet occupies on the wire.
+    pub fn wire_size(&self) -> usize {
+        let body = match self {
+            Packet::ReadRequest {
+                filename,
+                mode,
+                options,
+            }
+            | Packet::WriteRequest {
+                filename,
+                mode,
+                options,
+            } => filename.len() + mode.len() + 2 + options.wire_size(),
+            Packet::Data { payload, .. } => 2 + payload.len(),
+            Packet::Ack { .. } => 2,
+            Packet::Error { message, .. } => 2 + message.len() + 1,
+            Packet::OptionAck { options } => options.wire_size(),
+        };
+        2 + body
+    }
+
+    /// Writes the packet to the buffer, and returns the number of bytes written. Nothing is written if the buffer
+    /// is too small, or if a string contains a zero byte, which would end it early on the wire.
+    pub fn serialize(&self, buffer: &mut [u8]) -> Result<usize, PacketError> {
+        self.validate()?;
+        let needed = self.wire_size();
+        if buffer.len() < needed {
+            return Err(PacketError::BufferTooSmall {
+                needed,
+                available: buffer.len(),
+            });
+        }
+        let mut head = 0;
+        write_bytes(buffer, &mut head, &(self.opcode() as u16).to_be_bytes());
+        match self {
+            Packet::ReadRequest {
+                filename,
+                mode,
+                options,
+            }
+            | Packet::WriteRequest {
+                filename,
+                mode,
+                options,
+            } => {
+                write_string(buffer, &mut head, filename);
+                write_string(buffer, &mut head, mode);
+                options.write(buffer, &mut head);
+            }
+            Packet::Data { block, payload } => {
+                write_bytes(buffer, &mut head, &block.to_be_bytes());
+                write_bytes(buffer, &mut head, payload);
+            }
+            Packet::Ack { block } => write_bytes(buffer, &mut head, &block.to_be_bytes()),
+            Packet::Error { code, message } => {
+                write_bytes(buffer, &mut head, &code.to_be_bytes());
+                write_string(buffer, &mut head, message);
+            }
+            Packet::OptionAck { options } => options.write(buffer, &mut head),
+        }
+        Ok(head)
+    }
+
+    /// Checks that every string can be written out as is.
+    fn validate(&self) -> Result<(), PacketError> {
+        match self {
+            Packet::ReadRequest {
+                filename,
+                mode,
+                options,
+            }
+            | Packet::WriteRequest {
+                filename,
+                mode,
+                options,
+            } => {
+                validate_string(filename)?;
+                validate_string(mode)?;
+                options.validate()
+            }
+            Packet::Error { message, .. } => validate_string(message),
+            Packet::OptionAck { options } => options.validate(),
+            Packet::Data { .. } | Packet::Ack { .. } => Ok(()),
+        }
+    }
+}
+
+/// The options of a request or an option acknowledgement, as name and value pairs.
+///
+/// Parsed options borrow the wire format from the datagram. Options to send can be borrowed from a list of
+/// `TransferOption` instead.
+#[derive(Clone, Copy)]
+pub struct Options<'a>(OptionsSource<'a>);
+
+#[derive(Clone, Copy)]
+enum OptionsSource<'a> {
+    // Null-terminated names and values, already checked to be well formed.
+    Wire(&'a [u8]),
+    List(&'a [TransferOption]),
+}
+
+impl<'a> Options<'a> {
+    /// No options at all.
+    pub fn empty() -> Self {
+        Options(OptionsSource::List(&[]))
+    }
+
+    /// Checks the options at the end of a packet, and ignores any zeros that pad it if allowed.
+    fn parse(bytes: &'a [u8], padded: bool) -> Result<Self, PacketError> {
+        let mut rest = bytes;
+        while !is_end(rest, padded) {
+            let (name, after_name) = split_string(rest)?;
+            if name.is_empty() {
+                return Err(PacketError::EmptyOptionName);
+            }
+            let (_, after_value) = split_string(after_name)?;
+            rest = after_value;
+        }
+        Ok(Options(OptionsSource::Wire(
+            &bytes[0..bytes.len() - rest.len()],
+        )))
+    }
+
+    /// Iterates over the name and value of each option, in order.
+    pub fn iter(&self) -> OptionsIter<'a> {
+        match self.0 {
+            OptionsSource::Wire(bytes) => OptionsIter(OptionsIterSource::Wire(bytes)),
+            OptionsSource::List(list) => OptionsIter(OptionsIterSource::List(list.iter())),
+        }
+    }
+
+    /// Finds the value of an option by name, ignoring case.
+    pub fn get(&self, name: &str) -> Option<&'a str> {
+        self.iter()
+            .find(|(option, _)| option.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value)
+    }
+
+    /// The number of options.
+    pub fn len(&self) -> usize {
+        self.iter().count()
+    }
+
+    /// Indicates whether there are no options.
+    pub fn is_empty(&self) -> bool {
+        self.iter().next().is_none()
+    }
+
+    /// Copies the options into a list that owns them.
+    pub fn to_vec(&self) -> Vec<TransferOption> {
+        self.iter()
+            .map(|(name, value)| TransferOption::new(name, value))
+            .collect()
+    }
+
+    /// The number of bytes the options occupy on the wire.
+    fn wire_size(&self) -> usize {
+        self.iter()
+            .map(|(name, value)| name.len() + value.len() + 2)
+            .sum()
+    }
+
+    /// Checks that every option can be written out as is.
+    fn validate(&self) -> Result<(), PacketError> {
+        for (name, value) in self.iter() {
+            if name.is_empty() {
+                return Err(PacketError::EmptyOptionName);
+            }
+            validate_string(name)?;
+            validate_string(value)?;
+        }
+        Ok(())
+    }
+
+    /// Helper to write the options as null-terminated name and value strings.
+    fn write(&self, buffer: &mut [u8], head: &mut usize) {
+        for (name, value) in self.iter() {
+            write_string(buffer, head, name);
+            write_string(buffer, head, value);
+        }
+    }
+}
+
+impl<'a> From<&'a [TransferOption]> for Options<'a> {
+    fn from(options: &'a [TransferOption]) -> Self {
+        Options(OptionsSource::List(options))
+    }
+}
+
+impl<'a> From<&'a Vec<TransferOption>> for Options<'a> {
+    fn from(options: &'a Vec<TransferOption>) -> Self {
+        Options(OptionsSource::List(options))
+    }
+}
+
+impl fmt::Debug for Options<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_map().entries(self.iter()).finish()
+    }
+}
+
+impl PartialEq for Options<'_> {
+    fn eq(&self, other: &Self) -> bool {
+        self.iter().eq(other.iter())
+    }
+}
+
+impl Eq for Options<'_> {}
+
+impl<'a> IntoIterator for Options<'a> {
+    type Item = (&'a str, &'a str);
+    type IntoIter = OptionsIter<'a>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+/// An iterator over the name and value of each option.
+#[derive(Debug, Clone)]
+pub struct OptionsIter<'a>(OptionsIterSource<'a>);
+
+#[derive(Debug, Clone)]
+enum OptionsIterSource<'a> {
+    Wire(&'a [u8]),
+    List(slice::Iter<'a, TransferOption>),
+}
+
+impl<'a> Iterator for OptionsIter<'a> {
+    type Item = (&'a str, &'a str);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match &mut self.0 {
+            OptionsIterSource::Wire(rest) => {
+                let (name, after_name) = split_string(rest).ok()?;
+                let (value, after_value) = split_string(after_name).ok()?;
+                *rest = after_value;
+                Some((name, value))
+            }
+            OptionsIterSource::List(list) => list
+                .next()
+                .map(|option| (option.name.as_str(), option.value.as_str())),
+        }
+    }
+}
+
+/// Helper to parse the filename, mode and options of a request.
+fn parse_request(bytes: &[u8], padded: bool) -> Result<(&str, &str, Options<'_>), PacketError> {
+    let (filename, rest) = split_string(bytes)?;
+    let (mode, rest) = split_string(rest)?;
+    Ok((filename, mode, Options::parse(rest, padded)?))
+}
+
+/// Helper to split a big-endian 16-bit number off the front of the bytes.
+fn split_u16(bytes: &[u8]) -> Result<(u16, &[u8]), PacketError> {
+    match bytes {
+        [high, low, rest @ ..] => Ok((u16::from_be_bytes([*high, *low]), rest)),
+        _ => Err(PacketError::Truncated),
+    }
+}
+
+/// Helper to split a null-terminated string off the front of the bytes.
+fn split_string(bytes: &[u8]) -> Result<(&str, &[u8]), PacketError> {
+    let end = bytes
+        .iter()
+        .position(|byte| *byte == TERMINATOR_BYTE)
+        .ok_or(PacketError::MissingTerminator)?;
+    let string = str::from_utf8(&bytes[0..end]).map_err(|_| PacketError::InvalidString)?;
+    Ok((string, &bytes[end + 1..]))
+}
+
+/// Indicates whether the packet ends here, allowing for padding if asked to.
+fn is_end(bytes: &[u8], padded: bool) -> bool {
+    if padded {
+        bytes.iter().all(|byte| *byte == TERMINATOR_BYTE)
+    } else {
+        bytes.is_empty()
+    }
+}
+
+/// Helper to check that nothing, or nothing but padding if allowed, follows the end of a packet.
+fn expect_end(bytes: &[u8], padded: bool) -> Result<(), PacketError> {
+    if is_end(bytes, padded) {
+        Ok(())
+    } else {
+        Err(PacketError::TrailingBytes)
+    }
+}
+
+/// Helper to check that a string has no zero byte in it.
+fn validate_string(string: &str) -> Result<(), PacketError> {
+    if string.as_bytes().contains(&TERMINATOR_BYTE) {
+        Err(PacketError::InvalidString)
+    } else {
+        Ok(())
+    }
+}
+
+/// Helper to write a string and its terminator, and advance the head pointer.
+fn write_string(buffer: &mut [u8], head: &mut usize, string: &str) {
+    write_bytes(buffer, head, string.as_bytes());
+    write_bytes(buffer, head, &[TERMINATOR_BYTE]);
+}
+
+/// Helper to write bytes from source to buffer and advance the head pointer.
+fn write_bytes(buffer: &mut [u8], head: &mut usize, source: &[u8]) {
+    let count = source.len();
+    buffer[*head..*head + count].copy_from_slice(source);
+    *head += count;
+}
+
+mod test {
+    #[cfg(test)]
+    use super::*;
+
+    #[test]
+    fn test_parse_request() {
+        let packet =
+            Packet::parse(b"\x00\x01ABCDE\x00octet\x00blksize\x001024\x00tsize\x000\x00").unwrap();
+        match packet {
+            Packet::ReadRequest {
+                filename,
+                mode,
+                options,
+            } => {
+                assert_eq!(filename, "ABCDE");
+                assert_eq!(mode, "octet");
+                assert_eq!(options.len(), 2);
+                assert_eq!(options.get("BLKSIZE"), Some("1024"));
+                assert_eq!(options.get("tsize"), Some("0"));
+                assert_eq!(options.get("timeout"), None);
+            }
+            _ => panic!("Unexpected packet: {:?}", packet),
+        }
+    }
+
+    #[test]
+    fn test_parse_padded_request() {
+        let datagram = b"\x00\x02ABCDE\x00netascii\x00\x00\x00\x00";
+        let packet = Packet::parse_with(datagram, Strictness::Lenient).unwrap();
+        assert_eq!(
+            packet,
+            Packet::WriteRequest {
+                filename: "ABCDE",
+                mode: "netascii",
+                options: Options::empty(),
+            }
+        );
+        assert_eq!(
+            Packet::parse_with(b"\x00\x04\x00\x07\x00\x00", Strictness::Lenient),
+            Ok(Packet::Ack { block: 7 })
+        );
+
+        // Padding is only tolerated when lenient.
+        assert_eq!(Packet::parse(datagram), Err(PacketError::EmptyOptionName));
+        assert_eq!(
+            Packet::parse(b"\x00\x04\x00\x07\x00\x00"),
+            Err(PacketError::TrailingBytes)
+        );
+    }
+
+    #[test]
+    fn test_parse_data() {
+        let packet = Packet::parse(b"\x00\x03\x01\x02abc").unwrap();
+        assert_eq!(
+            packet,
+            Packet::Data {
+                block: 0x102,
+                payload: b"abc"
+            }
+        );
+        let packet = Packet::parse(b"\x00\x03\x00\x01").unwrap();
+        assert_eq!(
+            packet,
+            Packet::Data {
+                block: 1,
+                payload: b""
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_ack_and_error() {
+        assert_eq!(
+            Packet::parse(b"\x00\x04\x00\x07"),
+            Ok(Packet::Ack { block: 7 })
+        );
+        assert_eq!(
+            Packet::parse(b"\x00\x05\x00\x01Not found\x00"),
+            Ok(Packet::Error {
+                code: 1,
+                message: "Not found"
+            })
+        );
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert_eq!(Packet::parse(b""), Err(PacketError::Truncated));
+        assert_eq!(Packet::parse(b"\x00"), Err(PacketError::Truncated));
+        assert_eq!(
+            Packet::parse(b"\x00\x09"),
+            Err(PacketError::UnknownOpCode(9))
+        );
+        assert_eq!(Packet::parse(b"\x00\x03\x00"), Err(PacketError::Truncated));
+        assert_eq!(
+            Packet::parse(b"\x00\x04\x00\x01\x02"),
+            Err(PacketError::TrailingBytes)
+        );
+        assert_eq!(
+            Packet::parse(b"\x00\x01ABCDE\x00octet"),
+            Err(PacketError::MissingTerminator)
+        );
+        assert_eq!(
+            Packet::parse(b"\x00\x01AB\xFFDE\x00octet\x00"),
+            Err(PacketError::InvalidString)
+        );
+        assert_eq!(
+            Packet::parse(b"\x00\x06blksize\x00"),
+            Err(PacketError::MissingTerminator)
+        );
+        assert_eq!(
+            Packet::parse(b"\x00\x06\x00512\x00"),
+            Err(PacketError::EmptyOptionName)
+        );
+        assert_eq!(
+            Packet::parse(b"\x00\x05\x00\x01Not found"),
+            Err(PacketError::MissingTerminator)
+        );
+    }
+
+    #[test]
+    fn test_serialize_round_trip() {
+        let options = vec![
+            TransferOption::block_size(1024),
+            TransferOption::window_size(4),
+        ];
+        let packets = [
+            Packet::ReadRequest {
+                filename: "ABCDE",
+                mode: "octet",
+                options: Options::from(&options),
+            },
+            Packet::Data {
+                block: 3,
+                payload: b"Hello",
+            },
+            Packet::Ack { block: 3 },
+            Packet::Error {
+                code: 1,
+                message: "Not found",
+            },
+            Packet::OptionAck {
+                options: Options::from(&options),
+            },
+        ];
+        let mut buffer = [0u8; 64];
+        for packet in packets {
+            let count = packet.serialize(&mut buffer).unwrap();
+            assert_eq!(count, packet.wire_size());
+            assert_eq!(Packet::parse(&buffer[0..count]), Ok(packet));
+        }
+        assert_eq!(
+            Packet::parse(b"\x00\x06blksize\x001024\x00windowsize\x004\x00").unwrap(),
+            packets[4]
+        );
+    }
+
+    #[test]
+    fn test_serialize_errors() {
+        let mut buffer = [0u8; 8];
+        let packet = Packet::Data {
+            block: 1,
+            payload: b"Hello",
+        };
+        assert_eq!(
+            packet.serialize(&mut buffer),
+            Err(PacketError::BufferTooSmall {
+                needed: 9,
+                available: 8
+            })
+        );
+        let packet = Packet::Error {
+            code: 0,
+            message: "A\0B",
+        };
+        assert_eq!(
+            packet.serialize(&mut buffer),
+            Err(PacketError::InvalidString)
+        );
+        let options = vec![TransferOption::new("", "1")];
+        let packet = Packet::OptionAck {
+            options: Options::from(&options),
+        };
+        assert_eq!(
+            packet.serialize(&mut buffer),
+            Err(PacketError::EmptyOptionName)
+        );
+    }
+}
@@ -0,0 +1,597 @@
+//! Typed client and server transfers
+//!
+//! `Machine` serves every role, and leaves it to the host to call the methods that fit the direction of the
+//! transfer. The types here name the role instead, and only offer what is valid in it:
+//!
+//! * `ClientGet` downloads a file from a server, with a read request.
+//! * `ClientPut` uploads a file to a server, with a write request.
+//! * `ServerRead` serves a file to a client that sent a read request.
+//! * `ServerWrite` takes in a file from a client that sent a write request.
+//! * `ServerMail` takes in mail from a client that sent a write request in mail mode.
+//!
+//! A server request starts out `Pending`, while the host decides whether to accept it, and becomes `Active` once it
+//! does. If accepting or rejecting it fails before anything was sent, the pending request is handed back with the
+//! error, so that the host can still answer it. Each type dereferences to its `Machine` to read the state and progress of the transfer.
+
+use std::marker::PhantomData;
+use std::net::SocketAddr;
+use std::ops::Deref;
+use std::time::Instant;
+
+use crate::config::MachineConfig;
+use crate::constants::{ErrorCode, Mode, TransferType};
+use crate::errors::TftprsError;
+use crate::event::Event;
+use crate::file::{FileSink, FileSource};
+use crate::machine::Machine;
+use crate::options::TransferOption;
+use crate::state::TransferSummary;
+
+/// Why a pending request could not be accepted or rejected.
+#[derive(Debug)]
+pub enum Unanswered<T> {
+    /// Nothing was sent, such as when the transmit buffer is too small, and the request is handed back so that the
+    /// host can still answer it.
+    Pending {
+        /// The request, which is still pending.
+        request: Box<T>,
+        /// Why it could not be answered.
+        error: TftprsError,
+    },
+    /// The machine refused the request itself, such as a file larger than the host allows. The error for the client
+    /// is in the transmit buffer, with the length carried by the error, and there is nothing left to answer.
+    Ended(TftprsError),
+}
+
+impl<T> Unanswered<T> {
+    /// Why the request could not be answered, leaving the request behind.
+    pub fn into_error(self) -> TftprsError {
+        match self {
+            Unanswered::Pending { error, .. } | Unanswered::Ended(error) => error,
+        }
+    }
+}
+
+impl<'a, T: Deref<Target = Machine<'a>>> Unanswered<T> {
+    /// Hands the request back, unless the machine already ended the transfer.
+    fn new(request: T, error: TftprsError) -> Self {
+        if request.is_busy() {
+            Unanswered::Pending {
+                request: Box::new(request),
+                error,
+            }
+        } else {
+            Unanswered::Ended(error)
+        }
+    }
+}
+
+/// A server request that the host has yet to accept or reject.
+#[derive(Debug)]
+pub struct Pending;
+
+/// A transfer that is under way.
+#[derive(Debug)]
+pub struct Active;
+
+/// Starts transfers as a client, with a new machine for each.
+#[derive(Debug, Clone, Default)]
+pub struct Client {
+    // The settings of each new machine.
+    config: MachineConfig,
+}
+
+impl Client {
+    pub fn new(config: MachineConfig) -> Self {
+        Self { config }
+    }
+
+    /// Requests a file from the server, and writes the read request to the transmit buffer.
+    pub fn get<'a>(
+        &self,
+        now: Instant,
+        filename: String,
+        file: impl FileSink + Send + 'a,
+        outgoing: &mut [u8],
+    ) -> Result<(ClientGet<'a>, usize), TftprsError> {
+        let mut machine = Machine::with_config(self.config.clone())?;
+        let count = machine.request_receive_file(now, filename, file, outgoing)?;
+        Ok((ClientGet { machine }, count))
+    }
+
+    /// Offers a file to the server, and writes the write request to the transmit buffer.
+    pub fn put<'a>(
+        &self,
+        now: Instant,
+        filename: String,
+        file: impl FileSource + Send + 'a,
+        outgoing: &mut [u8],
+    ) -> Result<(ClientPut<'a>, usize), TftprsError> {
+        let mut machine = Machine::with_config(self.config.clone())?;
+        let count = machine.request_send_file(now, filename, file, outgoing)?;
+        Ok((ClientPut { machine }, count))
+    }
+}
+
+/// Answers requests as a server, with a new machine for each.
+#[derive(Debug, Clone, Default)]
+pub struct Server {
+    // The settings of each new machine.
+    config: MachineConfig,
+}
+
+impl Server {
+    pub fn new(config: MachineConfig) -> Self {
+        Self { config }
+    }
+
+    /// Parses a message that arrived at the server's port. If it is a request, the returned transfer is bound to
+    /// the client that sent it.
+    ///
+    /// Mail cannot be read, so a read request in mail mode is refused right away. The illegal operation error is
+    /// written to the transmit buffer, and reported with `TftprsError::IllegalOperation`.
+    pub fn listen<'a>(
+        &self,
+        from: SocketAddr,
+        received: &[u8],
+        outgoing: &mut [u8],
+    ) -> Result<IncomingRequest<'a>, TftprsError> {
+        let mut machine = Machine::with_config(self.config.clone())?;
+        let filename = machine.listen_for_request(from, received)?;
+        Ok(match (machine.transfer_type(), machine.mode()) {
+            (Some(TransferType::Write), Mode::Mail) => {
+                let count = machine.send_error(
+                    ErrorCode::IllegalOperation,
+                    outgoing,
+                    String::from("Mail cannot be read"),
+                )?;
+                return Err(TftprsError::IllegalOperation(count));
+            }
+            (Some(TransferType::Write), _) => IncomingRequest::Read(ServerRead {
+                machine,
+                filename,
+                phase: PhantomData,
+            }),
+            (_, Mode::Mail) => IncomingRequest::Mail(ServerMail {
+                machine,
+                recipient: filename,
+                phase: PhantomData,
+            }),
+            _ => IncomingRequest::Write(ServerWrite {
+                machine,
+                filename,
+                phase: PhantomData,
+            }),
+        })
+    }
+}
+
+/// A request from a client, by what the client wants to do.
+#[derive(Debug)]
+pub enum IncomingRequest<'a> {
+    /// The client wants to read a file from the server.
+    Read(ServerRead<'a, Pending>),
+    /// The client wants to write a file to the server.
+    Write(ServerWrite<'a, Pending>),
+    /// The client wants to send mail to a recipient on the server.
+    Mail(ServerMail<'a, Pending>),
+}
+
+/// A client download of a file.
+#[derive(Debug)]
+pub struct ClientGet<'a> {
+    machine: Machine<'a>,
+}
+
+impl<'a> ClientGet<'a> {
+    /// Processes a message from the server. See `Machine::process()`.
+    pub fn process(
+        &mut self,
+        now: Instant,
+        from: SocketAddr,
+        received: &[u8],
+        outgoing: &mut [u8],
+    ) -> Result<Event, TftprsError> {
+        self.machine.process(now, from, received, outgoing)
+    }
+
+    /// Acknowledges again if the server went quiet. See `Machine::handle_timeout()`.
+    pub fn handle_timeout(
+        &mut self,
+        now: Instant,
+        outgoing: &mut [u8],
+    ) -> Result<Event, TftprsError> {
+        self.machine.handle_timeout(now, outgoing)
+    }
+
+    /// Cancels the download, and discards the partial file. See `Machine::cancel()`.
+    pub fn cancel(
+        mut self,
+        reason: String,
+        outgoing: &mut [u8],
+    ) -> Result<TransferSummary, TftprsError> {
+        self.machine.cancel(reason, outgoing)
+    }
+
+    /// Gives up the typed interface.
+    pub fn into_machine(self) -> Machine<'a> {
+        self.machine
+    }
+}
+
+impl<'a> Deref for ClientGet<'a> {
+    type Target = Machine<'a>;
+
+    fn deref(&self) -> &Machine<'a> {
+        &self.machine
+    }
+}
+
+/// A client upload of a file.
+#[derive(Debug)]
+pub struct ClientPut<'a> {
+    machine: Machine<'a>,
+}
+
+impl<'a> ClientPut<'a> {
+    /// Processes a message from the server. See `Machine::process()`.
+    pub fn process(
+        &mut self,
+        now: Instant,
+        from: SocketAddr,
+        received: &[u8],
+        outgoing: &mut [u8],
+    ) -> Result<Event, TftprsError> {
+        self.machine.process(now, from, received, outgoing)
+    }
+
+    /// Writes out the rest of the current window. See `Machine::poll_transmit()`.
+    pub fn poll_transmit(&mut self, outgoing: &mut [u8]) -> Result<Option<usize>, TftprsError> {
+        self.machine.poll_transmit(outgoing)
+    }
+
+    /// Retransmits if the server went quiet. See `Machine::handle_timeout()`.
+    pub fn handle_timeout(
+        &mut self,
+        now: Instant,
+        outgoing: &mut [u8],
+    ) -> Result<Event, TftprsError> {
+        self.machine.handle_timeout(now, outgoing)
+    }
+
+    /// Cancels the upload. See `Machine::cancel()`.
+    pub fn cancel(
+        mut self,
+        reason: String,
+        outgoing: &mut [u8],
+    ) -> Result<TransferSummary, TftprsError> {
+        self.machine.cancel(reason, outgoing)
+    }
+
+    /// Gives up the typed interface.
+    pub fn into_machine(self) -> Machine<'a> {
+        self.machine
+    }
+}
+
+impl<'a> Deref for ClientPut<'a> {
+    type Target = Machine<'a>;
+
+    fn deref(&self) -> &Machine<'a> {
+        &self.machine
+    }
+}
+
+/// A file served to a client.
+#[derive(Debug)]
+pub struct ServerRead<'a, Phase = Active> {
+    machine: Machine<'a>,
+    // The file the client asked for.
+    filename: String,
+    // Whether the request was accepted, which decides what can be done with it.
+    phase: PhantomData<Phase>,
+}
+
+impl<'a, Phase> ServerRead<'a, Phase> {
+    /// The file the client asked for.
+    pub fn filename(&self) -> &str {
+        &self.filename
+    }
+
+    /// Gives up the typed interface.
+    pub fn into_machine(self) -> Machine<'a> {
+        self.machine
+    }
+}
+
+impl<'a> ServerRead<'a, Pending> {
+    /// Accepts an option the client requested. See `Machine::accept_option()`.
+    pub fn accept_option(&mut self, option: TransferOption) -> Result<(), TftprsError> {
+        self.machine.accept_option(option)
+    }
+
+    /// Starts sending the file. See `Machine::reply_send_file()`.
+    pub fn accept(
+        mut self,
+        now: Instant,
+        file: impl FileSource + Send + 'a,
+        outgoing: &mut [u8],
+    ) -> Result<(ServerRead<'a, Active>, usize), Unanswered<Self>> {
+        match self.machine.reply_send_file(now, file, outgoing) {
+            Ok(count) => Ok((self.activate(), count)),
+            Err(error) => Err(Unanswered::new(self, error)),
+        }
+    }
+
+    /// Refuses the request, such as when the file does not exist, and writes the error to the transmit buffer.
+    pub fn reject(
+        mut self,
+        code: ErrorCode,
+        message: String,
+        outgoing: &mut [u8],
+    ) -> Result<usize, Unanswered<Self>> {
+        match self.machine.send_error(code, outgoing, message) {
+            Ok(count) => Ok(count),
+            Err(error) => Err(Unanswered::new(self, error)),
+        }
+    }
+
+    /// Moves on to the transfer once the request was accepted.
+    fn activate(self) -> ServerRead<'a, Active> {
+        ServerRead {
+            machine: self.machine,
+            filename: self.filename,
+            phase: PhantomData,
+        }
+    }
+}
+
+impl ServerRead<'_, Active> {
+    /// Processes a message from the client. See `Machine::process()`.
+    pub fn process(
+        &mut self,
+        now: Instant,
+        from: SocketAddr,
+        received: &[u8],
+        outgoing: &mut [u8],
+    ) -> Result<Event, TftprsError> {
+        self.machine.process(now, from, received, outgoing)
+    }
+
+    /// Writes out the rest of the current window. See `Machine::poll_transmit()`.
+    pub fn poll_transmit(&mut self, outgoing: &mut [u8]) -> Result<Option<usize>, TftprsError> {
+        self.machine.poll_transmit(outgoing)
+    }
+
+    /// Retransmits if the client went quiet. See `Machine::handle_timeout()`.
+    pub fn handle_timeout(
+        &mut self,
+        now: Instant,
+        outgoing: &mut [u8],
+    ) -> Result<Event, TftprsError> {
+        self.machine.handle_timeout(now, outgoing)
+    }
+
+    /// Cancels the transfer. See `Machine::cancel()`.
+    pub fn cancel(
+        mut self,
+        reason: String,
+        outgoing: &mut [u8],
+    ) -> Result<TransferSummary, TftprsError> {
+        self.machine.cancel(reason, outgoing)
+    }
+}
+
+impl<'a, Phase> Deref for ServerRead<'a, Phase> {
+    type Target = Machine<'a>;
+
+    fn deref(&self) -> &Machine<'a> {
+        &self.machine
+    }
+}
+
+/// A file taken in from a client.
+#[derive(Debug)]
+pub struct ServerWrite<'a, Phase = Active> {
+    machine: Machine<'a>,
+    // The file the client is sending.
+    filename: String,
+    // Whether the request was accepted, which decides what can be done with it.
+    phase: PhantomData<Phase>,
+}
+
+impl<'a, Phase> ServerWrite<'a, Phase> {
+    /// The file the client is sending.
+    pub fn filename(&self) -> &str {
+        &self.filename
+    }
+
+    /// Gives up the typed interface.
+    pub fn into_machine(self) -> Machine<'a> {
+        self.machine
+    }
+}
+
+impl<'a> ServerWrite<'a, Pending> {
+    /// Accepts an option the client requested. See `Machine::accept_option()`.
+    pub fn accept_option(&mut self, option: TransferOption) -> Result<(), TftprsError> {
+        self.machine.accept_option(option)
+    }
+
+    /// Starts receiving the file. See `Machine::reply_receive_file()`.
+    pub fn accept(
+        mut self,
+        now: Instant,
+        file: impl FileSink + Send + 'a,
+        outgoing: &mut [u8],
+    ) -> Result<(ServerWrite<'a, Active>, usize), Unanswered<Self>> {
+        match self.machine.reply_receive_file(now, file, outgoing) {
+            Ok(count) => Ok((self.activate(), count)),
+            Err(error) => Err(Unanswered::new(self, error)),
+        }
+    }
+
+    /// Refuses the request, such as when the file already exists, and writes the error to the transmit buffer.
+    pub fn reject(
+        mut self,
+        code: ErrorCode,
+        message: String,
+        outgoing: &mut [u8],
+    ) -> Result<usize, Unanswered<Self>> {
+        match self.machine.send_error(code, outgoing, message) {
+            Ok(count) => Ok(count),
+            Err(error) => Err(Unanswered::new(self, error)),
+        }
+    }
+
+    /// Moves on to the transfer once the request was accepted.
+    fn activate(self) -> ServerWrite<'a, Active> {
+        ServerWrite {
+            machine: self.machine,
+            filename: self.filename,
+            phase: PhantomData,
+        }
+    }
+}
+
+impl ServerWrite<'_, Active> {
+    /// Processes a message from the client. See `Machine::process()`.
+    pub fn process(
+        &mut self,
+        now: Instant,
+        from: SocketAddr,
+        received: &[u8],
+        outgoing: &mut [u8],
+    ) -> Result<Event, TftprsError> {
+        self.machine.process(now, from, received, outgoing)
+    }
+
+    /// Acknowledges again if the client went quiet. See `Machine::handle_timeout()`.
+    pub fn handle_timeout(
+        &mut self,
+        now: Instant,
+        outgoing: &mut [u8],
+    ) -> Result<Event, TftprsError> {
+        self.machine.handle_timeout(now, outgoing)
+    }
+
+    /// Cancels the transfer, and discards the partial file. See `Machine::cancel()`.
+    pub fn cancel(
+        mut self,
+        reason: String,
+        outgoing: &mut [u8],
+    ) -> Result<TransferSummary, TftprsError> {
+        self.machine.cancel(reason, outgoing)
+    }
+}
+
+impl<'a, Phase> Deref for ServerWrite<'a, Phase> {
+    type Target = Machine<'a>;
+
+    fn deref(&self) -> &Machine<'a> {
+        &self.machine
+    }
+}
+
+/// Mail taken in from a client.
+#[derive(Debug)]
+pub struct ServerMail<'a, Phase = Active> {
+    machine: Machine<'a>,
+    // The recipient of the mail.
+    recipient: String,
+    // Whether the request was accepted, which decides what can be done with it.
+    phase: PhantomData<Phase>,
+}
+
+impl<'a, Phase> ServerMail<'a, Phase> {
+    /// The recipient of the mail.
+    pub fn recipient(&self) -> &str {
+        &self.recipient
+    }
+
+    /// Gives up the typed interface.
+    pub fn into_machine(self) -> Machine<'a> {
+        self.machine
+    }
+}
+
+impl<'a> ServerMail<'a, Pending> {
+    /// Accepts an option the client requested. See `Machine::accept_option()`.
+    pub fn accept_option(&mut self, option: TransferOption) -> Result<(), TftprsError> {
+        self.machine.accept_option(option)
+    }
+
+    /// Starts receiving the mail. See `Machine::reply_receive_mail()`.
+    pub fn accept(
+        mut self,
+        now: Instant,
+        sink: impl FnMut(&str, &[u8]) + Send + 'a,
+        outgoing: &mut [u8],
+    ) -> Result<(ServerMail<'a, Active>, usize), Unanswered<Self>> {
+        match self.machine.reply_receive_mail(now, sink, outgoing) {
+            Ok(count) => Ok((self.activate(), count)),
+            Err(error) => Err(Unanswered::new(self, error)),
+        }
+    }
+
+    /// Refuses the mail, such as when the recipient is unknown, and writes the error to the transmit buffer.
+    pub fn reject(
+        mut self,
+        code: ErrorCode,
+        message: String,
+        outgoing: &mut [u8],
+    ) -> Result<usize, Unanswered<Self>> {
+        match self.machine.send_error(code, outgoing, message) {
+            Ok(count) => Ok(count),
+            Err(error) => Err(Unanswered::new(self, error)),
+        }
+    }
+
+    /// Moves on to the transfer once the request was accepted.
+    fn activate(self) -> ServerMail<'a, Active> {
+        ServerMail {
+            machine: self.machine,
+            recipient: self.recipient,
+            phase: PhantomData,
+        }
+    }
+}
+
+impl ServerMail<'_, Active> {
+    /// Processes a message from the client. See `Machine::process()`.
+    pub fn process(
+        &mut self,
+        now: Instant,
+        from: SocketAddr,
+        received: &[u8],
+        outgoing: &mut [u8],
+    ) -> Result<Event, TftprsError> {
+        self.machine.process(now, from, received, outgoing)
+    }
+
+    /// Acknowledges again if the client went quiet. See `Machine::handle_timeout()`.
+    pub fn handle_timeout(
+        &mut self,
+        now: Instant,
+        outgoing: &mut [u8],
+    ) -> Result<Event, TftprsError> {
+        self.machine.handle_timeout(now, outgoing)
+    }
+
+    /// Cancels the transfer, and discards the partial message. See `Machine::cancel()`.
+    pub fn cancel(
+        mut self,
+        reason: String,
+        outgoing: &mut [u8],
+    ) -> Result<TransferSummary, TftprsError> {
+        self.machine.cancel(reason, outgoing)
+    }
+}
+
+impl<'a, Phase> Deref for ServerMail<'a, Phase> {
+    type Target = Machine<'a>;
+
+    fn deref(&self) -> &Machine<'a> {
+        &self.machine
+    }
+}
@@ -1,27 +1,34 @@
 //! Serialization of messages
 
-use crate::constants::BINARY_MODE;
 use crate::constants::FIXED_REQUEST_BYTES;
-use crate::constants::MAX_DATA_SIZE;
-use crate::constants::MAX_PACKET_SIZE;
-use crate::constants::TEXT_MODE;
+use crate::constants::MAX_REQUEST_SIZE;
+#[cfg(test)]
 use std::cmp::min;
 
 use crate::constants::ErrorCode;
 use crate::constants::Mode;
-use crate::constants::OpCode;
 use crate::constants::TransferType;
 
 use crate::errors::TftprsError;
+use crate::options::TransferOption;
+use crate::packet::{Options, Packet};
 
 pub(crate) trait Serial {
-    fn serialize(&self, buffer: &mut [u8; MAX_PACKET_SIZE]) -> usize;
+    /// The packet that carries this message.
+    fn packet(&self) -> Packet<'_>;
+
+    /// Writes the message to the buffer, and returns the number of bytes written, or 0 if it does not fit.
+    /// The machine writes packets itself to report errors, so this is only for building messages in tests.
+    #[cfg(test)]
+    fn serialize(&self, buffer: &mut [u8]) -> usize {
+        self.packet().serialize(buffer).unwrap_or(0)
+    }
 }
 
 /// Any transfer begins with a request to read or write a file, which also serves to request a connection.
 ///
 /// The size of filename must not exceed `match mode { binary => 503, text => 500 }` bytes
-/// `(512 - 4 fixed - mode string)`
+/// `(512 - 4 fixed - mode string)`, less the size of any options.
 ///
 /// The request will take ownership of the filename.
 #[derive(Debug, Clone)]
@@ -33,16 +40,20 @@ pub(crate) struct Request {
     // The mode field contains the string "netascii", "octet", or "mail" (or any combination of upper
     //    and lower case, such as "NETASCII", NetAscii", etc.) in netascii indicating the three modes defined in the protocol.
     mode: Mode,
+    // Options per RFC 2347, appended after the mode.
+    options: Vec<TransferOption>,
 }
 
 impl Request {
     fn filename_fits(mode: Mode, filename: &str) -> bool {
-        let mode_size = match mode {
-            Mode::Text => TEXT_MODE.len(),
-            Mode::Binary => BINARY_MODE.len(),
-        };
-        let max_filename_size = MAX_PACKET_SIZE - FIXED_REQUEST_BYTES - mode_size;
-        filename.len() <= max_filename_size
+        Request::request_fits(mode, filename, &[])
+    }
+
+    fn request_fits(mode: Mode, filename: &str, options: &[TransferOption]) -> bool {
+        let mode_size = mode.wire_name().len();
+        let options_size: usize = options.iter().map(TransferOption::wire_size).sum();
+        let max_filename_size = MAX_REQUEST_SIZE - FIXED_REQUEST_BYTES - mode_size;
+        filename.len() + options_size <= max_filename_size
     }
 
     pub(crate) fn new(
@@ -55,67 +66,93 @@ impl Request {
                 request,
                 filename,
                 mode,
+                options: Vec::new(),
             })
         } else {
             Err(TftprsError::BadRequestAttempted)
         }
     }
+
+    /// Appends options to the request. The whole request must still fit in a single packet.
+    pub(crate) fn with_options(
+        mut self,
+        options: Vec<TransferOption>,
+    ) -> Result<Self, TftprsError> {
+        if Request::request_fits(self.mode, &self.filename, &options) {
+            self.options = options;
+            Ok(self)
+        } else {
+            Err(TftprsError::BadRequestAttempted)
+        }
+    }
 }
 
 impl Serial for Request {
-    fn serialize(&self, buffer: &mut [u8; MAX_PACKET_SIZE]) -> usize {
-        if !Request::filename_fits(self.mode, &self.filename) {
+    #[cfg(test)]
+    fn serialize(&self, buffer: &mut [u8]) -> usize {
+        if !Request::request_fits(self.mode, &self.filename, &self.options) {
             return 0;
         }
-        let mut head = 0;
-        write_bytes(buffer, &mut head, &(self.request as u16).to_be_bytes());
-        write_bytes(buffer, &mut head, self.filename.as_bytes());
-        write_bytes(buffer, &mut head, &[0x0]);
-        let mode_string = match self.mode {
-            Mode::Text => TEXT_MODE,
-            Mode::Binary => BINARY_MODE,
-        };
-        write_bytes(buffer, &mut head, mode_string.as_bytes());
-        write_bytes(buffer, &mut head, &[0x0]);
-        head
+        self.packet().serialize(buffer).unwrap_or(0)
+    }
+
+    fn packet(&self) -> Packet<'_> {
+        let filename = &self.filename;
+        let mode = self.mode.wire_name();
+        let options = Options::from(&self.options);
+        match self.request {
+            TransferType::Read => Packet::ReadRequest {
+                filename,
+                mode,
+                options,
+            },
+            TransferType::Write => Packet::WriteRequest {
+                filename,
+                mode,
+                options,
+            },
+        }
     }
 }
 
+/// Each data packet carries one block of the file. Every block but the last is full, i.e., of the block size.
 #[derive(Debug, Clone)]
 pub(crate) struct Data<'a> {
+    // The block number as it appears on the wire.
     block: u16,
-    data: &'a Vec<u8>,
+    payload: &'a [u8],
 }
 
 impl<'a> Data<'a> {
-    pub(crate) fn new(block: u16, data: &'a Vec<u8>) -> Option<Self> {
-        if block == 0 {
-            return None;
-        }
-        if (block - 1) as usize * MAX_DATA_SIZE > data.len() {
-            return None;
-        }
-        Some(Self { block, data })
+    pub(crate) fn new(block: u16, payload: &'a [u8]) -> Self {
+        Self { block, payload }
     }
+}
 
-    fn offset(&self) -> usize {
-        (self.block - 1) as usize * MAX_DATA_SIZE
+impl<'a> Serial for Data<'a> {
+    fn packet(&self) -> Packet<'_> {
+        Packet::Data {
+            block: self.block,
+            payload: self.payload,
+        }
     }
 }
 
-impl<'a> Serial for Data<'a> {
-    fn serialize(&self, buffer: &mut [u8; MAX_PACKET_SIZE]) -> usize {
-        let mut head = 0;
-        write_bytes(buffer, &mut head, &(OpCode::Data as u16).to_be_bytes());
-        write_bytes(buffer, &mut head, &self.block.to_be_bytes());
-        let count = min(MAX_DATA_SIZE, self.data.len() - self.offset());
-        write_bytes(
-            buffer,
-            &mut head,
-            &self.data[self.offset()..self.offset() + count],
-        );
-        head
+/// Takes the given block of a file, counting from block 1. The block after the last full block is empty
+/// if the file is a whole number of blocks. There is no block past that.
+#[cfg(test)]
+pub(crate) fn file_block(file: &[u8], block: u64, block_size: usize) -> Option<&[u8]> {
+    if block == 0 {
+        return None;
+    }
+    let offset = usize::try_from(block - 1)
+        .ok()
+        .and_then(|index| index.checked_mul(block_size))?;
+    if offset > file.len() {
+        return None;
     }
+    let count = min(block_size, file.len() - offset);
+    Some(&file[offset..offset + count])
 }
 
 pub(crate) struct Ack {
@@ -129,15 +166,8 @@ impl Ack {
 }
 
 impl Serial for Ack {
-    fn serialize(&self, buffer: &mut [u8; MAX_PACKET_SIZE]) -> usize {
-        let mut head = 0;
-        write_bytes(
-            buffer,
-            &mut head,
-            &(OpCode::Acknowledgement as u16).to_be_bytes(),
-        );
-        write_bytes(buffer, &mut head, &self.block.to_be_bytes());
-        head
+    fn packet(&self) -> Packet<'_> {
+        Packet::Ack { block: self.block }
     }
 }
 
@@ -161,25 +191,39 @@ impl ErrorResponse {
 }
 
 impl Serial for ErrorResponse {
-    fn serialize(&self, buffer: &mut [u8; MAX_PACKET_SIZE]) -> usize {
-        let mut head = 0;
-        write_bytes(buffer, &mut head, &(OpCode::Error as u16).to_be_bytes());
-        write_bytes(buffer, &mut head, &(self.code as u16).to_be_bytes());
-        write_bytes(buffer, &mut head, self.message.as_bytes());
-        head
+    fn packet(&self) -> Packet<'_> {
+        Packet::Error {
+            code: self.code as u16,
+            message: &self.message,
+        }
+    }
+}
+
+/// The option acknowledgement (OACK) lists the options the listening side accepted from a request.
+#[derive(Debug, Clone)]
+pub(crate) struct OptionAck {
+    options: Vec<TransferOption>,
+}
+
+impl OptionAck {
+    pub fn new(options: Vec<TransferOption>) -> Self {
+        Self { options }
     }
 }
 
-/// Helper to write bytes from source to buffer and advance the head pointer.
-fn write_bytes(buffer: &mut [u8; MAX_PACKET_SIZE], head: &mut usize, source: &[u8]) {
-    let count = source.len();
-    buffer[*head..*head + count].copy_from_slice(source);
-    *head += count;
+impl Serial for OptionAck {
+    fn packet(&self) -> Packet<'_> {
+        Packet::OptionAck {
+            options: Options::from(&self.options),
+        }
+    }
 }
 
 mod test {
     #[cfg(test)]
     use super::*;
+    #[cfg(test)]
+    use crate::constants::{DEFAULT_BLOCK_SIZE, FIXED_DATA_BYTES, MAX_PACKET_SIZE};
     #[test]
     fn test_read_request() {
         let request = Request::new(TransferType::Read, Mode::Binary, String::from("ABCDE"));
@@ -208,74 +252,135 @@ mod test {
         let request = Request::new(
             TransferType::Write,
             Mode::Binary,
-            String::from(['H'; 512].iter().collect::<String>()),
+            ['H'; 512].iter().collect::<String>(),
         );
         assert!(request.is_err());
     }
 
+    #[test]
+    fn test_request_with_options() {
+        let request = Request::new(TransferType::Read, Mode::Binary, String::from("A"))
+            .unwrap()
+            .with_options(vec![TransferOption::new("blksize", "1428")])
+            .unwrap();
+        let mut tx_buffer = [0u8; MAX_PACKET_SIZE];
+        let count = request.serialize(&mut tx_buffer);
+        let expected = b"\x00\x01A\x00OCTET\x00blksize\x001428\x00";
+        assert_eq!(count, expected.len());
+        assert_eq!(expected, &tx_buffer[0..count]);
+
+        // Options count against the size of the packet.
+        let request = Request::new(TransferType::Read, Mode::Binary, String::from("A"))
+            .unwrap()
+            .with_options(vec![TransferOption::new(
+                "x",
+                ['H'; 512].iter().collect::<String>(),
+            )]);
+        assert!(request.is_err());
+    }
+
+    #[test]
+    fn test_option_ack() {
+        let oack = OptionAck::new(vec![
+            TransferOption::new("blksize", "8"),
+            TransferOption::new("tsize", "0"),
+        ]);
+        let mut tx_buffer = [0u8; MAX_PACKET_SIZE];
+        let count = oack.serialize(&mut tx_buffer);
+        let expected = b"\x00\x06blksize\x008\x00tsize\x000\x00";
+        assert_eq!(expected, &tx_buffer[0..count]);
+    }
+
     #[test]
     fn test_one_small_gram_data() {
         let my_datagram: Vec<u8> = vec![0x5a, 0xa5];
-        let data = Data::new(1, &my_datagram);
+        let data = Data::new(1, file_block(&my_datagram, 1, DEFAULT_BLOCK_SIZE).unwrap());
         let mut tx_buffer = [0u8; MAX_PACKET_SIZE];
-        data.unwrap().serialize(&mut tx_buffer);
+        data.serialize(&mut tx_buffer);
         let expected: [u8; 6] = [0x0, 0x3, 0x0, 0x1, 0x5a, 0xa5];
         assert_eq!(expected, tx_buffer[0..6]);
 
         // cannot send a second one
-        let data = Data::new(2, &my_datagram);
-        assert!(data.is_none());
+        assert!(file_block(&my_datagram, 2, DEFAULT_BLOCK_SIZE).is_none());
     }
 
     #[test]
     fn test_full_packet_data() {
-        let my_datagram: Vec<u8> = vec![0x5A; MAX_DATA_SIZE];
-        let data = Data::new(1, &my_datagram);
+        let my_datagram: Vec<u8> = vec![0x5A; DEFAULT_BLOCK_SIZE];
+        let data = Data::new(1, file_block(&my_datagram, 1, DEFAULT_BLOCK_SIZE).unwrap());
         let mut tx_buffer = [0u8; MAX_PACKET_SIZE];
-        data.unwrap().serialize(&mut tx_buffer);
-        let mut expected: [u8; MAX_DATA_SIZE] = [0x5A; MAX_DATA_SIZE];
+        let count = data.serialize(&mut tx_buffer);
+        assert_eq!(count, DEFAULT_BLOCK_SIZE + FIXED_DATA_BYTES);
+        let mut expected: [u8; DEFAULT_BLOCK_SIZE + FIXED_DATA_BYTES] =
+            [0x5A; DEFAULT_BLOCK_SIZE + FIXED_DATA_BYTES];
         expected[0] = 0x0;
         expected[1] = 0x3;
         expected[2] = 0x0;
         expected[3] = 0x1;
-        assert_eq!(expected, tx_buffer[0..MAX_DATA_SIZE]);
+        assert_eq!(expected, tx_buffer[0..count]);
     }
 
     #[test]
     fn test_full_packet_data_and_one() {
-        let mut my_datagram: Vec<u8> = vec![0x5A; MAX_DATA_SIZE + 1];
-        my_datagram[MAX_DATA_SIZE] = 0xA5;
+        let mut my_datagram: Vec<u8> = vec![0x5A; DEFAULT_BLOCK_SIZE + 1];
+        my_datagram[DEFAULT_BLOCK_SIZE] = 0xA5;
         let mut tx_buffer = [0u8; MAX_PACKET_SIZE];
 
         // first datagram
-        let data = Data::new(1, &my_datagram);
-        data.unwrap().serialize(&mut tx_buffer);
-        let mut expected: [u8; MAX_DATA_SIZE] = [0x5A; MAX_DATA_SIZE];
+        let data = Data::new(1, file_block(&my_datagram, 1, DEFAULT_BLOCK_SIZE).unwrap());
+        let count = data.serialize(&mut tx_buffer);
+        let mut expected: [u8; DEFAULT_BLOCK_SIZE + FIXED_DATA_BYTES] =
+            [0x5A; DEFAULT_BLOCK_SIZE + FIXED_DATA_BYTES];
         expected[0] = 0x0;
         expected[1] = 0x3;
         expected[2] = 0x0;
         expected[3] = 0x1;
-        assert_eq!(expected, tx_buffer[0..MAX_DATA_SIZE]);
+        assert_eq!(expected, tx_buffer[0..count]);
 
         // second datagram
-        let data = Data::new(2, &my_datagram);
-        data.unwrap().serialize(&mut tx_buffer);
+        let data = Data::new(2, file_block(&my_datagram, 2, DEFAULT_BLOCK_SIZE).unwrap());
+        data.serialize(&mut tx_buffer);
         let expected: [u8; 5] = [0x0, 0x3, 0x0, 0x2, 0xA5];
         assert_eq!(expected, tx_buffer[0..5]);
     }
 
     #[test]
     fn test_three_packets() {
-        let mut my_datagram: Vec<u8> = vec![0x5A; MAX_DATA_SIZE * 2 + 1];
-        my_datagram[MAX_DATA_SIZE * 2] = 0xA5;
+        let mut my_datagram: Vec<u8> = vec![0x5A; DEFAULT_BLOCK_SIZE * 2 + 1];
+        my_datagram[DEFAULT_BLOCK_SIZE * 2] = 0xA5;
         let mut tx_buffer = [0u8; MAX_PACKET_SIZE];
 
-        let data = Data::new(3, &my_datagram);
-        data.unwrap().serialize(&mut tx_buffer);
+        let data = Data::new(3, file_block(&my_datagram, 3, DEFAULT_BLOCK_SIZE).unwrap());
+        data.serialize(&mut tx_buffer);
         let expected: [u8; 5] = [0x0, 0x3, 0x0, 0x3, 0xA5];
         assert_eq!(expected, tx_buffer[0..5]);
     }
 
+    #[test]
+    fn test_negotiated_block_size_data() {
+        let my_datagram: Vec<u8> = (0..20).collect();
+        let mut tx_buffer = [0u8; MAX_PACKET_SIZE];
+
+        let data = Data::new(2, file_block(&my_datagram, 2, 8).unwrap());
+        let count = data.serialize(&mut tx_buffer);
+        let expected: [u8; 12] = [0x0, 0x3, 0x0, 0x2, 8, 9, 10, 11, 12, 13, 14, 15];
+        assert_eq!(expected, tx_buffer[0..count]);
+
+        let data = Data::new(3, file_block(&my_datagram, 3, 8).unwrap());
+        let count = data.serialize(&mut tx_buffer);
+        let expected: [u8; 8] = [0x0, 0x3, 0x0, 0x3, 16, 17, 18, 19];
+        assert_eq!(expected, tx_buffer[0..count]);
+    }
+
+    #[test]
+    fn test_empty_last_block() {
+        let my_datagram: Vec<u8> = vec![0x5A; 16];
+        assert_eq!(file_block(&my_datagram, 2, 8).unwrap().len(), 8);
+        assert!(file_block(&my_datagram, 3, 8).unwrap().is_empty());
+        assert!(file_block(&my_datagram, 4, 8).is_none());
+        assert!(file_block(&my_datagram, 0, 8).is_none());
+    }
+
     #[test]
     fn test_ack() {
         let my_ack = Ack::new(0);
@@ -0,0 +1,45 @@
+//! Phases and progress of a transfer
+
+use crate::constants::TransferType;
+
+/// The phase of the transfer, as seen by the host. Blocks are counted from the start of the transfer, without regard
+/// to the rollover of the block field on the wire.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub enum TransferState {
+    /// No transfer has been started since the machine was created or reset.
+    #[default]
+    Idle,
+    /// A request from the remote peer was accepted with `listen_for_request()`, and the host has yet to reply.
+    RequestReceived,
+    /// The host sent a request, and the remote peer has not answered yet.
+    RequestSent,
+    /// The host is sending a file, and waits for the peer to acknowledge the window that ends at the given block,
+    /// however much of the window the host has transmitted so far. Block 0 is the option acknowledgement. When the
+    /// length of the file is not known ahead, as in netascii mode, the window only ends early at the final block once
+    /// that block is read.
+    AwaitingAck(u64),
+    /// The host is receiving a file, and waits for the given block.
+    AwaitingData(u64),
+    /// The host received the whole file and acknowledged the final block, and stays around to acknowledge it again
+    /// in case the peer did not get the ack.
+    Dallying,
+    /// The last transfer completed successfully.
+    Completed,
+    /// The last transfer ended with an error, a timeout, or a failure of the file.
+    Failed,
+}
+
+/// What a transfer had achieved when the host cancelled it.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct TransferSummary {
+    /// The kind of transfer, from the perspective of the host.
+    pub transfer_type: TransferType,
+    /// The phase the transfer was in.
+    pub state: TransferState,
+    /// The number of bytes of the file that were sent and acknowledged, or received.
+    pub bytes: u64,
+    /// The number of blocks that were sent and acknowledged, or received.
+    pub blocks: u64,
+    /// The length of the error message in the transmit buffer, which must still be sent to the peer.
+    pub transmit: usize,
+}
@@ -0,0 +1,216 @@
+//! Retransmission timer
+//!
+//! The timer does not read a clock. The host passes in the current time, so the machine stays deterministic.
+//!
+//! By default, the timer waits a fixed interval. When it adapts, the interval follows the measured round trip time,
+//! as RFC 1123 recommends for TFTP, using the estimator of RFC 6298. Round trips of retransmitted messages are not
+//! measured (Karn's rule), and each retransmission doubles the interval. The doubled interval is kept until the reply
+//! to a message that went out only once can be measured (RFC 6298 section 5.7), so that the estimate also learns a
+//! round trip time longer than the first interval.
+
+use std::time::{Duration, Instant};
+
+use crate::constants::{
+    DEFAULT_MAX_RETRIES, DEFAULT_RETRANSMIT_TIMEOUT, MAX_RETRANSMIT_TIMEOUT, MIN_RETRANSMIT_TIMEOUT,
+};
+use crate::errors::TftprsError;
+
+/// Round trip time measurements of the replies from the peer.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct RttStats {
+    /// The smoothed round trip time, once there is a measurement.
+    pub srtt: Option<Duration>,
+    /// The mean deviation of the round trip time.
+    pub rttvar: Duration,
+    /// The last round trip time measured.
+    pub latest: Option<Duration>,
+    /// The number of round trips measured.
+    pub samples: u64,
+}
+
+impl RttStats {
+    /// Adds a measurement to the estimate.
+    pub(crate) fn update(&mut self, rtt: Duration) {
+        match self.srtt {
+            None => {
+                self.srtt = Some(rtt);
+                self.rttvar = rtt / 2;
+            }
+            Some(srtt) => {
+                self.rttvar = (self.rttvar * 3 + srtt.abs_diff(rtt)) / 4;
+                self.srtt = Some((srtt * 7 + rtt) / 8);
+            }
+        }
+        self.latest = Some(rtt);
+        self.samples += 1;
+    }
+}
+
+/// Tracks when the last message is due for retransmission, and how many times it has been retransmitted.
+#[derive(Debug, Clone)]
+pub(crate) struct RetransmitTimer {
+    // The interval configured by the host, which is also the first estimate when the timer adapts.
+    pub(crate) interval: Duration,
+    // The number of consecutive retransmissions allowed before the transfer fails.
+    pub(crate) max_retries: u32,
+    // Whether the interval follows the measured round trip time.
+    pub(crate) adaptive: bool,
+    // When the last message is due for retransmission. The timer is stopped if this is None.
+    deadline: Option<Instant>,
+    // The number of consecutive retransmissions so far.
+    retries: u32,
+    // The number of times the adaptive interval doubled since the last round trip was measured.
+    backoff: u32,
+    // When the message awaiting a reply was sent, unless it has been retransmitted since.
+    sent_at: Option<Instant>,
+    // The round trip time measured so far. It carries over to the next transfer.
+    rtt: RttStats,
+}
+
+impl Default for RetransmitTimer {
+    fn default() -> Self {
+        Self {
+            interval: DEFAULT_RETRANSMIT_TIMEOUT,
+            max_retries: DEFAULT_MAX_RETRIES,
+            adaptive: false,
+            deadline: None,
+            retries: 0,
+            backoff: 0,
+            sent_at: None,
+            rtt: RttStats::default(),
+        }
+    }
+}
+
+impl RetransmitTimer {
+    /// Restarts the timer after the transfer made progress, and times the message that goes out with it. A fixed
+    /// timeout, such as a negotiated one, takes the place of the configured or estimated interval.
+    pub(crate) fn start(&mut self, now: Instant, timeout: Option<Duration>) {
+        self.extend(now, timeout);
+        self.sent_at = Some(now);
+    }
+
+    /// Restarts the timer without sending anything, such as while the rest of a window arrives.
+    pub(crate) fn extend(&mut self, now: Instant, timeout: Option<Duration>) {
+        self.retries = 0;
+        self.deadline = Some(now + timeout.unwrap_or_else(|| self.rto()));
+    }
+
+    /// Stops the timer when nothing is left to retransmit.
+    pub(crate) fn stop(&mut self) {
+        self.deadline = None;
+        self.retries = 0;
+        self.sent_at = None;
+    }
+
+    /// Measures the round trip of the message that was answered, unless it was retransmitted. A measurement ends
+    /// the backoff.
+    pub(crate) fn sample(&mut self, now: Instant) {
+        if let Some(sent_at) = self.sent_at.take() {
+            self.rtt.update(now.saturating_duration_since(sent_at));
+            self.backoff = 0;
+        }
+    }
+
+    /// When the last message is due for retransmission.
+    pub(crate) fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    /// The number of consecutive retransmissions so far.
+    pub(crate) fn retries(&self) -> u32 {
+        self.retries
+    }
+
+    /// The round trip time measured so far.
+    pub(crate) fn rtt(&self) -> RttStats {
+        self.rtt
+    }
+
+    /// The retransmission timeout, which is the configured interval unless the timer adapts. An adaptive timeout
+    /// doubles with every retransmission, until the next round trip is measured.
+    pub(crate) fn rto(&self) -> Duration {
+        if !self.adaptive {
+            return self.interval;
+        }
+        let estimate = match self.rtt.srtt {
+            Some(srtt) => srtt + self.rtt.rttvar * 4,
+            None => self.interval,
+        };
+        estimate
+            .clamp(MIN_RETRANSMIT_TIMEOUT, MAX_RETRANSMIT_TIMEOUT)
+            .saturating_mul(1 << self.backoff.min(16))
+            .min(MAX_RETRANSMIT_TIMEOUT)
+    }
+
+    /// Checks whether the deadline passed. If it did, the timer is restarted for the retransmission, unless the
+    /// retries are used up.
+    pub(crate) fn expire(
+        &mut self,
+        now: Instant,
+        timeout: Option<Duration>,
+    ) -> Result<bool, TftprsError> {
+        match self.deadline {
+            Some(deadline) if now >= deadline => {
+                if self.retries >= self.max_retries {
+                    self.stop();
+                    return Err(TftprsError::Timeout);
+                }
+                self.retries += 1;
+                self.backoff = self.backoff.saturating_add(1);
+                // A reply could answer either transmission, so it says nothing about the round trip.
+                self.sent_at = None;
+                self.deadline = Some(now + timeout.unwrap_or_else(|| self.rto()));
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+}
+
+mod test {
+    #[cfg(test)]
+    use super::*;
+
+    #[test]
+    fn test_rtt_estimate() {
+        let mut rtt = RttStats::default();
+        rtt.update(Duration::from_millis(100));
+        assert_eq!(rtt.srtt, Some(Duration::from_millis(100)));
+        assert_eq!(rtt.rttvar, Duration::from_millis(50));
+        rtt.update(Duration::from_millis(200));
+        assert_eq!(rtt.srtt, Some(Duration::from_micros(112_500)));
+        assert_eq!(rtt.rttvar, Duration::from_micros(62_500));
+        assert_eq!(rtt.latest, Some(Duration::from_millis(200)));
+        assert_eq!(rtt.samples, 2);
+    }
+
+    #[test]
+    fn test_adaptive_backoff() {
+        let start = Instant::now();
+        let mut timer = RetransmitTimer {
+            adaptive: true,
+            ..Default::default()
+        };
+        timer.start(start, None);
+        timer.sample(start + Duration::from_millis(100));
+        assert_eq!(timer.rto(), Duration::from_millis(300));
+        timer.start(start, None);
+        assert_eq!(
+            timer.expire(start + Duration::from_millis(300), None),
+            Ok(true)
+        );
+        assert_eq!(timer.rto(), Duration::from_millis(600));
+        assert_eq!(timer.deadline(), Some(start + Duration::from_millis(900)));
+        // The reply to a retransmission is not measured, and the timeout stays doubled.
+        timer.sample(start + Duration::from_millis(400));
+        assert_eq!(timer.rtt().samples, 1);
+        timer.start(start + Duration::from_millis(400), None);
+        assert_eq!(timer.retries(), 0);
+        assert_eq!(timer.rto(), Duration::from_millis(600));
+        // A measured round trip ends the backoff.
+        timer.sample(start + Duration::from_millis(500));
+        assert_eq!(timer.rtt().samples, 2);
+        assert_eq!(timer.rto(), Duration::from_millis(250));
+    }
+}